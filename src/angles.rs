@@ -0,0 +1,117 @@
+use crate::config::{Angle, Button, GateShape, Modifier};
+use crate::dir8::Dir8;
+use crate::dpad::JoyStickState;
+use crate::state::{JoyButtons, Modifiers};
+use fixed::types::I1F7;
+
+impl Modifier {
+    #[inline]
+    pub fn held(self, m: Modifiers) -> bool {
+        match self {
+            Modifier::Mod1 => m.mod1(),
+            Modifier::Mod2 => m.mod2(),
+        }
+    }
+
+    /// Whether the modifiers that are held are exactly `modifiers`.
+    #[inline]
+    pub fn exactly(modifiers: &[Modifier], m: Modifiers) -> bool {
+        [Modifier::Mod1, Modifier::Mod2]
+            .iter()
+            .all(|&k| modifiers.contains(&k) == k.held(m))
+    }
+}
+
+impl Button {
+    #[inline]
+    pub fn held(self, btn: JoyButtons) -> bool {
+        match self {
+            Button::A => btn.a(),
+            Button::B => btn.b(),
+            Button::X => btn.x(),
+            Button::Y => btn.y(),
+            Button::Z => btn.z(),
+            Button::Start => btn.start(),
+            Button::L => btn.l(),
+            Button::R => btn.r(),
+        }
+    }
+
+    #[inline]
+    pub fn set(self, btn: &mut JoyButtons, value: bool) {
+        match self {
+            Button::A => btn.set_a(value),
+            Button::B => btn.set_b(value),
+            Button::X => btn.set_x(value),
+            Button::Y => btn.set_y(value),
+            Button::Z => btn.set_z(value),
+            Button::Start => btn.set_start(value),
+            Button::L => btn.set_l(value),
+            Button::R => btn.set_r(value),
+        }
+    }
+}
+
+impl Angle {
+    #[inline]
+    fn matches(&self, m: Modifiers, btn: JoyButtons, dir: Dir8) -> bool {
+        self.directions.contains(&dir)
+            && Modifier::exactly(&self.modifiers, m)
+            && self.buttons.iter().all(|b| b.held(btn))
+    }
+}
+
+/// Looks up the control stick coordinates for `dir`. Out of the entries that match, the one that
+/// needs the most buttons wins, and ties go to the earlier entry, whose coordinates are used as
+/// they are. Without any match the stick is pushed all the way in `dir` and clamped to `gate`.
+pub fn lookup(
+    table: &[Angle],
+    gate: GateShape,
+    m: Modifiers,
+    btn: JoyButtons,
+    dir: Dir8,
+) -> (I1F7, I1F7) {
+    let mut best: Option<&Angle> = None;
+    for angle in table.iter().filter(|a| a.matches(m, btn, dir)) {
+        if best.is_none_or(|b| angle.buttons.len() > b.buttons.len()) {
+            best = Some(angle);
+        }
+    }
+    match best {
+        Some(angle) => mirror(Some(dir), angle.x, angle.y),
+        None => {
+            let (vx, vy) = dir.vector();
+            gate.clamp((I1F7::saturating_from_num(vx), I1F7::saturating_from_num(vy)))
+        }
+    }
+}
+
+/// Turns coordinates written for up-right, with y going up, into stick coordinates pointing
+/// towards `dir`.
+#[inline]
+pub fn mirror(dir: Option<Dir8>, x: I1F7, y: I1F7) -> (I1F7, I1F7) {
+    let (vx, vy) = dir.map_or((0, 0), Dir8::vector);
+    (
+        if vx < 0 { x.saturating_neg() } else { x },
+        if vy > 0 { y } else { y.saturating_neg() },
+    )
+}
+
+impl JoyStickState {
+    /// Points the stick wherever the angle table says for its current direction.
+    #[inline]
+    pub fn update_angle(
+        &mut self,
+        m: Modifiers,
+        btn: JoyButtons,
+        table: &[Angle],
+        gate: GateShape,
+    ) {
+        let (x, y) = match Dir8::of(&self.dpad) {
+            Some(dir) => lookup(table, gate, m, btn, dir),
+            None => (I1F7::ZERO, I1F7::ZERO),
+        };
+        self.x = x;
+        self.y = y;
+    }
+}
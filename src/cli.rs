@@ -0,0 +1,77 @@
+use crate::replay::Source;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: melee-vpad [COMMAND]
+
+commands:
+    run [--tui]         drive the virtual pads (the default), optionally with a live dashboard
+    record <log> [--tui]
+                        like run, but also log every input, state update and output to <log>
+    check               report problems with the config and the keyboards that are plugged in
+    setup               pick a keyboard and press a key for every bind, then write the config
+    replay <log> [--outputs]
+                        play a recording back into the virtual pads with its original timing,
+                        either by feeding its key events through the current config, or with
+                        --outputs by sending the recorded outputs as they are
+    dtm <log> <movie>   convert a recording into a Dolphin movie, sampling its outputs at 60Hz
+    play <movie>        play a Dolphin movie into the virtual pads at 60Hz
+    verify <log> <slp>  compare a recording against a Slippi replay of the same game and report
+                        every frame where the game saw something other than what was sent
+";
+
+pub enum Command {
+    Run { record: Option<PathBuf>, tui: bool },
+    Check,
+    Setup,
+    Replay { log: PathBuf, source: Source },
+    Dtm { log: PathBuf, movie: PathBuf },
+    Play { movie: PathBuf },
+    Verify { log: PathBuf, replay: PathBuf },
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.peekable();
+    let cmd = args.next();
+    let mut arg = |name: &str| args.next().ok_or(format!("missing {}", name));
+    let mut command = match cmd.as_deref() {
+        None | Some("run") => Command::Run {
+            record: None,
+            tui: false,
+        },
+        Some("record") => Command::Run {
+            record: Some(arg("<log>")?.into()),
+            tui: false,
+        },
+        Some("check") => Command::Check,
+        Some("setup") => Command::Setup,
+        Some("replay") => {
+            let log = arg("<log>")?.into();
+            let source = match args.next().as_deref() {
+                None => Source::Keys,
+                Some("--outputs") => Source::Outputs,
+                Some(other) => return Err(format!("unexpected argument {:?}", other)),
+            };
+            Command::Replay { log, source }
+        }
+        Some("dtm") => Command::Dtm {
+            log: arg("<log>")?.into(),
+            movie: arg("<movie>")?.into(),
+        },
+        Some("play") => Command::Play {
+            movie: arg("<movie>")?.into(),
+        },
+        Some("verify") => Command::Verify {
+            log: arg("<log>")?.into(),
+            replay: arg("<slp>")?.into(),
+        },
+        Some(other) => return Err(format!("unknown command {:?}", other)),
+    };
+    if let Command::Run { tui, .. } = &mut command {
+        *tui = args.next_if(|arg| arg == "--tui").is_some();
+    }
+    match args.next() {
+        Some(extra) => Err(format!("unexpected argument {:?}", extra)),
+        None => Ok(command),
+    }
+}
@@ -1,41 +1,268 @@
+use crate::dir8::Dir8;
 use evdev_rs::enums::EV_KEY;
 use fixed::types::I1F7;
+use serde::de::IntoDeserializer;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationMilliSecondsWithFrac};
+use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use xdg;
 
+pub const CONFIG_FILE: &str = "melee-vpad.toml";
+
+// what sat at the top level before there were players, with `keyboard_path` from before `devices`
+const PLAYER_KEYS: [&str; 6] = [
+    "grab",
+    "output",
+    "dolphin_pipe_path",
+    "devices",
+    "binds",
+    "keyboard_path",
+];
+
+// the tuning from before the angle table, which can't be carried over into it
+const ANGLE_KEYS: [&str; 4] = ["mod1_incr", "mod1_around_y", "mod2_x_mul", "mod2_y_mul"];
+
 pub const JOY_UP_RANGE: i32 = 127;
 pub const JOY_DOWN_RANGE: i32 = -JOY_UP_RANGE;
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
-    pub keyboard_path: String,
     #[serde_as(as = "DurationMilliSecondsWithFrac")]
     pub poll_rate: Duration,
-    pub mod1_incr: I1F7,
-    pub mod1_around_y: I1F7,
     pub mod1_trigger_mul: I1F7,
-    pub mod2_x_mul: I1F7,
-    pub mod2_y_mul: I1F7,
+    /// Shape of the stick gates that full presses are clamped to. Angle table and rule coordinates
+    /// are used as they are
+    pub gate: GateShape,
+    /// How opposing directions that are held at the same time are resolved
+    pub socd: SocdSettings,
+    /// Exact control stick coordinates for modifier, direction and button combinations
+    pub angles: Vec<Angle>,
+    /// Overrides on top of the angle table; see `Rule`
+    pub rules: Vec<Rule>,
+    /// One virtual pad is created for each player
+    pub players: Vec<Player>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GateShape {
+    /// No clamping; diagonals reach the corners
+    Square,
+    Circle,
+    /// The octagonal gate of a GameCube controller, with diagonals at (0.7, 0.7)
+    Octagon,
+}
+
+/// Control stick coordinates to use when exactly `modifiers` are held, the stick points in one of
+/// `directions` and all of `buttons` are held. The coordinates are given as if pointing up-right,
+/// with y going up, and are mirrored for the other directions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Angle {
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
+    pub directions: Vec<Dir8>,
+    #[serde(default)]
+    pub buttons: Vec<Button>,
+    pub x: I1F7,
+    pub y: I1F7,
+}
+
+/// Overrides outputs whenever `when` holds. Rules are checked in order after the angle table has
+/// been applied, and every rule that holds applies its overrides, so where two rules set the same
+/// output the later one wins. The gate is applied last.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub when: Condition,
+    pub then: Override,
+}
+
+/// Every field that is given has to hold.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Condition {
+    /// Exactly these modifiers are held
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modifiers: Option<Vec<Modifier>>,
+    /// All of these buttons are held
+    pub buttons: Vec<Button>,
+    /// At least one of these buttons is held
+    pub any_buttons: Vec<Button>,
+    /// None of these buttons are held
+    pub not_buttons: Vec<Button>,
+    /// The stick points in one of these directions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_stick: Option<Vec<Dir8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c_stick: Option<Vec<Dir8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpad: Option<Vec<Dir8>>,
+}
+
+/// Stick coordinates are written like in the angle table, and mirrored towards wherever that
+/// stick is pointing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Override {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_stick: Option<Coords>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c_stick: Option<Coords>,
+    pub press: Vec<Button>,
+    pub release: Vec<Button>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Coords {
+    pub x: I1F7,
+    pub y: I1F7,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Modifier {
+    Mod1,
+    Mod2,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Button {
+    A,
+    B,
+    X,
+    Y,
+    Z,
+    Start,
+    L,
+    R,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocdSettings {
+    pub control_stick: AxisSocd,
+    pub c_stick: AxisSocd,
+    pub dpad: AxisSocd,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AxisSocd {
+    pub x: SocdMode,
+    pub y: SocdMode,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SocdMode {
+    /// The direction pressed last wins; releasing it brings back the other one if it is still held
+    LastInput,
+    /// The direction pressed last wins; releasing it leaves the axis neutral
+    LastInputNoReactivation,
+    /// Holding both directions cancels them out
+    Neutral,
+    /// The direction pressed first wins
+    FirstInput,
+    /// Up or left always wins
+    Negative,
+    /// Down or right always wins
+    Positive,
+}
+
+impl Default for SocdMode {
+    fn default() -> SocdMode {
+        SocdMode::LastInput
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Player {
+    /// Name of the virtual pad. Give every player a different one, so Dolphin can tell them apart
+    pub name: String,
+    pub port: u8,
+    /// Grab the keyboard exclusively, passing through only the keys that aren't bound
+    pub grab: bool,
+    pub output: OutputKind,
+    /// Where the Dolphin pipe is created. Left out, it is `Pipes/melee-vpad<port>` in Dolphin's
+    /// user directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dolphin_pipe_path: Option<String>,
+    /// Pressing this chord switches to the next profile, and from the last one back to the
+    /// settings without any profile
+    pub profile_cycle: Chord,
+    /// Every input device that matches one of these drives the pad, including ones plugged in later
+    pub devices: Vec<DeviceMatch>,
     pub binds: Binds,
+    pub profiles: Vec<Profile>,
+}
+
+/// A named set of overrides, e.g. for another game. Whatever is left out is taken from the player
+/// and the top-level settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mod1_trigger_mul: Option<I1F7>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socd: Option<SocdSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub angles: Option<Vec<Angle>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rules: Option<Vec<Rule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binds: Option<Binds>,
+}
+
+/// Matches input devices on every field that is given. One without any fields matches every
+/// keyboard, but nothing else.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceMatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phys: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputKind {
+    /// A uinput gamepad, mapped in Dolphin like any other evdev controller
+    Uinput,
+    /// Dolphin's pipe input protocol, written to `dolphin_pipe_path`
+    DolphinPipe,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Binds {
-    pub a: EV_KEY,
-    pub b: EV_KEY,
-    pub z: EV_KEY,
-    pub x: EV_KEY,
-    pub y: EV_KEY,
-    pub l: EV_KEY,
-    pub r: EV_KEY,
-    pub start: EV_KEY,
-    pub mod1: EV_KEY,
-    pub mod2: EV_KEY,
+    pub a: Keys,
+    pub b: Keys,
+    pub z: Keys,
+    pub x: Keys,
+    pub y: Keys,
+    pub l: Keys,
+    pub r: Keys,
+    pub start: Keys,
+    pub mod1: Keys,
+    pub mod2: Keys,
+    /// Pressing this chord switches between pad and typing mode
+    #[serde(default)]
+    pub typing_toggle: Chord,
     pub control_stick: DPad8Binds,
     pub c_stick: DPadBinds,
     pub dpad: DPadBinds,
@@ -43,87 +270,467 @@ pub struct Binds {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DPadBinds {
-    pub up: EV_KEY,
-    pub down: EV_KEY,
-    pub left: EV_KEY,
-    pub right: EV_KEY,
+    pub up: Keys,
+    pub down: Keys,
+    pub left: Keys,
+    pub right: Keys,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DPad8Binds {
-    pub upleft: EV_KEY,
-    pub up: EV_KEY,
-    pub upright: EV_KEY,
-    pub downleft: EV_KEY,
-    pub down: EV_KEY,
-    pub downright: EV_KEY,
-    pub left: EV_KEY,
-    pub right: EV_KEY,
+    pub upleft: Keys,
+    pub up: Keys,
+    pub upright: Keys,
+    pub downleft: Keys,
+    pub down: Keys,
+    pub downright: Keys,
+    pub left: Keys,
+    pub right: Keys,
+}
+
+/// Keys that have to be held together, written as `"KEY_LEFTCTRL+KEY_T"`. An empty string is a
+/// chord of no keys, which is never pressed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Chord(pub Vec<EV_KEY>);
+
+/// Any one of these chords triggers the action. A single chord can be written on its own, e.g.
+/// `a = "KEY_J"` or `a = ["KEY_J", "KEY_KP1"]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "KeysRepr", into = "KeysRepr")]
+pub struct Keys(pub Vec<Chord>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum KeysRepr {
+    One(Chord),
+    Many(Vec<Chord>),
+}
+
+impl TryFrom<String> for Chord {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Chord, String> {
+        if s.trim().is_empty() {
+            return Ok(Chord::default());
+        }
+        let keys = s
+            .split('+')
+            .map(|name| {
+                let name = name.trim();
+                EV_KEY::deserialize(name.into_deserializer())
+                    .map_err(|_: serde::de::value::Error| format!("unknown key {:?}", name))
+            })
+            .collect::<Result<Vec<EV_KEY>, String>>()?;
+        Ok(Chord(keys))
+    }
+}
+
+impl From<Chord> for String {
+    fn from(chord: Chord) -> String {
+        chord.to_string()
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<KeysRepr> for Keys {
+    fn from(repr: KeysRepr) -> Keys {
+        match repr {
+            KeysRepr::One(chord) => Keys(vec![chord]),
+            KeysRepr::Many(chords) => Keys(chords),
+        }
+    }
+}
+
+impl From<Keys> for KeysRepr {
+    fn from(keys: Keys) -> KeysRepr {
+        match <[Chord; 1]>::try_from(keys.0) {
+            Ok([chord]) => KeysRepr::One(chord),
+            Err(chords) => KeysRepr::Many(chords),
+        }
+    }
+}
+
+impl From<EV_KEY> for Keys {
+    fn from(key: EV_KEY) -> Keys {
+        Keys(vec![Chord(vec![key])])
+    }
+}
+
+impl fmt::Display for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, chord) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", chord)?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
-            keyboard_path: "/dev/input/by-id/usb-CATEX_TECH._84EC-XRGB_CA2017090002-event-kbd"
-                .to_string(),
             poll_rate: Duration::from_micros(250),
-            mod1_incr: I1F7::saturating_from_num(0.3875_f32),
-            mod1_around_y: I1F7::saturating_from_num(0.31_f32),
             mod1_trigger_mul: I1F7::saturating_from_num(129.0 / 256.0 as f32),
-            mod2_x_mul: I1F7::saturating_from_num(48.0 / 128.0 as f32),
-            mod2_y_mul: I1F7::saturating_from_num(48.0 / 128.0 as f32),
+            gate: GateShape::Octagon,
+            socd: SocdSettings::default(),
+            angles: default_angles(),
+            rules: default_rules(),
+            players: vec![Player::default()],
+        }
+    }
+}
+
+fn default_angles() -> Vec<Angle> {
+    use Dir8::*;
+    let angle = |modifiers: &[Modifier], directions: &[Dir8], buttons: &[Button], x, y| Angle {
+        modifiers: modifiers.to_vec(),
+        directions: directions.to_vec(),
+        buttons: buttons.to_vec(),
+        x: I1F7::saturating_from_num::<f32>(x),
+        y: I1F7::saturating_from_num::<f32>(y),
+    };
+    let diagonals = [NE, NW, SE, SW];
+    let (mod1, mod2) = (Modifier::Mod1, Modifier::Mod2);
+    vec![
+        // mod1: tilts, and shallow angles for firefox and wavedashes
+        angle(&[mod1], &[E, W], &[], 0.6625, 0.0),
+        angle(&[mod1], &[N, S], &[], 0.0, 0.5375),
+        angle(&[mod1], &diagonals, &[], 0.7375, 0.3125),
+        angle(&[mod1], &diagonals, &[Button::L], 0.6375, 0.375),
+        angle(&[mod1], &diagonals, &[Button::R], 0.6375, 0.375),
+        angle(&[mod1], &diagonals, &[Button::B], 0.9125, 0.3875),
+        // mod2: slow walks and steep angles
+        angle(&[mod2], &[E, W], &[], 0.3375, 0.0),
+        angle(&[mod2], &[N, S], &[], 0.0, 0.7375),
+        angle(&[mod2], &diagonals, &[], 0.3125, 0.7375),
+        angle(&[mod2], &diagonals, &[Button::L], 0.475, 0.875),
+        angle(&[mod2], &diagonals, &[Button::R], 0.475, 0.875),
+        angle(&[mod2], &diagonals, &[Button::B], 0.3875, 0.9125),
+    ]
+}
+
+fn default_rules() -> Vec<Rule> {
+    vec![
+        // shield drop
+        Rule {
+            when: Condition {
+                modifiers: Some(vec![Modifier::Mod2]),
+                any_buttons: vec![Button::L, Button::R],
+                control_stick: Some(vec![Dir8::S]),
+                ..Condition::default()
+            },
+            then: Override {
+                control_stick: Some(Coords {
+                    x: I1F7::ZERO,
+                    y: I1F7::saturating_from_num(0.43_f32),
+                }),
+                ..Override::default()
+            },
+        },
+    ]
+}
+
+impl Default for Player {
+    fn default() -> Player {
+        Player {
+            name: "melee-vpad".to_string(),
+            port: 1,
+            grab: false,
+            output: OutputKind::Uinput,
+            dolphin_pipe_path: None,
+            profile_cycle: Chord(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_F11]),
+            // filled in by `melee-vpad setup`
+            devices: Vec::new(),
             binds: Binds {
-                a: EV_KEY::KEY_J,
-                b: EV_KEY::KEY_K,
-                z: EV_KEY::KEY_L,
-                x: EV_KEY::KEY_SPACE,
-                y: EV_KEY::KEY_LEFTALT, // I don't really use this at all
-                start: EV_KEY::KEY_T,
-                l: EV_KEY::KEY_I,
-                r: EV_KEY::KEY_O,
-                mod1: EV_KEY::KEY_LEFTSHIFT,
-                mod2: EV_KEY::KEY_SLASH,
+                a: EV_KEY::KEY_J.into(),
+                b: EV_KEY::KEY_K.into(),
+                z: EV_KEY::KEY_L.into(),
+                x: EV_KEY::KEY_SPACE.into(),
+                y: EV_KEY::KEY_LEFTALT.into(), // I don't really use this at all
+                start: EV_KEY::KEY_T.into(),
+                l: EV_KEY::KEY_I.into(),
+                r: EV_KEY::KEY_O.into(),
+                mod1: EV_KEY::KEY_LEFTSHIFT.into(),
+                mod2: EV_KEY::KEY_SLASH.into(),
+                typing_toggle: Chord(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_F12]),
                 control_stick: DPad8Binds {
-                    up: EV_KEY::KEY_W,
-                    upleft: EV_KEY::KEY_Q,
-                    upright: EV_KEY::KEY_E,
-                    down: EV_KEY::KEY_S,
-                    downleft: EV_KEY::KEY_Z,
-                    downright: EV_KEY::KEY_C,
-                    left: EV_KEY::KEY_A,
-                    right: EV_KEY::KEY_D,
+                    up: EV_KEY::KEY_W.into(),
+                    upleft: EV_KEY::KEY_Q.into(),
+                    upright: EV_KEY::KEY_E.into(),
+                    down: EV_KEY::KEY_S.into(),
+                    downleft: EV_KEY::KEY_Z.into(),
+                    downright: EV_KEY::KEY_C.into(),
+                    left: EV_KEY::KEY_A.into(),
+                    right: EV_KEY::KEY_D.into(),
                 },
                 c_stick: DPadBinds {
-                    up: EV_KEY::KEY_H,
-                    down: EV_KEY::KEY_N,
-                    left: EV_KEY::KEY_B,
-                    right: EV_KEY::KEY_M,
+                    up: EV_KEY::KEY_H.into(),
+                    down: EV_KEY::KEY_N.into(),
+                    left: EV_KEY::KEY_B.into(),
+                    right: EV_KEY::KEY_M.into(),
                 },
                 dpad: DPadBinds {
-                    up: EV_KEY::KEY_UP,
-                    down: EV_KEY::KEY_DOWN,
-                    left: EV_KEY::KEY_LEFT,
-                    right: EV_KEY::KEY_RIGHT,
+                    up: EV_KEY::KEY_UP.into(),
+                    down: EV_KEY::KEY_DOWN.into(),
+                    left: EV_KEY::KEY_LEFT.into(),
+                    right: EV_KEY::KEY_RIGHT.into(),
                 },
             },
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl Player {
+    /// `dolphin_pipe_path`, or the default one for this player's port.
+    pub fn pipe_path(&self) -> PathBuf {
+        match &self.dolphin_pipe_path {
+            Some(path) => PathBuf::from(path),
+            None => xdg::BaseDirectories::with_prefix("dolphin-emu")
+                .map(|dirs| dirs.get_data_home().join("Pipes"))
+                .unwrap_or_default()
+                .join(format!("melee-vpad{}", self.port)),
         }
     }
 }
 
 impl Settings {
+    pub fn path() -> Result<PathBuf, Box<dyn Error>> {
+        Ok(xdg::BaseDirectories::new()?.place_config_file(CONFIG_FILE)?)
+    }
+
     pub fn new() -> Result<Settings, Box<dyn Error>> {
-        let pathbuf = xdg::BaseDirectories::new()?.place_config_file("melee-vpad.toml")?;
+        let pathbuf = Settings::path()?;
         let cfg = if pathbuf.exists() {
-            let contents = fs::read_to_string(pathbuf)?;
-            let cfg = toml::from_str(&contents)?;
-            cfg
+            Settings::load(&pathbuf)?
         } else {
             log::info!("Creating config file from defaults {:?}", pathbuf);
+            log::info!("Run `melee-vpad setup` to pick a keyboard and binds");
             let def = Settings::default();
-            fs::write(pathbuf, toml::to_string_pretty(&def)?)?;
+            def.save()?;
             def
         };
         log::info!("{:#?}", cfg);
         Ok(cfg)
     }
+
+    /// These settings as they are with `profile` switched on for `player`.
+    pub fn with_profile(&self, player: usize, profile: &Profile) -> Settings {
+        let mut s = self.clone();
+        if let Some(mul) = profile.mod1_trigger_mul {
+            s.mod1_trigger_mul = mul;
+        }
+        if let Some(socd) = &profile.socd {
+            s.socd = socd.clone();
+        }
+        if let Some(angles) = &profile.angles {
+            s.angles = angles.clone();
+        }
+        if let Some(rules) = &profile.rules {
+            s.rules = rules.clone();
+        }
+        if let Some(binds) = &profile.binds {
+            s.players[player].binds = binds.clone();
+        }
+        s
+    }
+
+    pub fn load(path: &Path) -> Result<Settings, Box<dyn Error>> {
+        Settings::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(contents: &str) -> Result<Settings, Box<dyn Error>> {
+        let mut value: toml::Value = toml::from_str(contents)?;
+        if let Some(table) = value.as_table_mut() {
+            migrate(table)?;
+        }
+        Ok(value.try_into()?)
+    }
+
+    /// Writes the settings to the config file. Comments in it are lost.
+    pub fn save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let pathbuf = Settings::path()?;
+        fs::write(&pathbuf, self.to_toml()?)?;
+        Ok(pathbuf)
+    }
+
+    // TOML wants the plain values of a table before its subtables, which the field order of e.g.
+    // `Override` and `Player` doesn't give. A `toml::Value` sorts them out when written.
+    fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string_pretty(&toml::Value::try_from(self)?)
+    }
+}
+
+/// Moves the player keys of a config file from before there were players into a single player,
+/// and drops the tuning from before the angle table.
+fn migrate(table: &mut toml::value::Table) -> Result<(), Box<dyn Error>> {
+    let dropped: Vec<&str> = ANGLE_KEYS
+        .iter()
+        .copied()
+        .filter(|&key| table.remove(key).is_some())
+        .collect();
+    if !dropped.is_empty() {
+        log::warn!(
+            "The config has {}, which the angle table replaced. They are ignored; put the \
+             coordinates they stood for into `angles`",
+            dropped.join(", ")
+        );
+    }
+    let mut old = Vec::new();
+    let mut player = toml::value::Table::new();
+    for &key in PLAYER_KEYS.iter() {
+        let value = match table.remove(key) {
+            Some(value) => value,
+            None => continue,
+        };
+        old.push(key);
+        if key == "keyboard_path" {
+            let mut device = toml::value::Table::new();
+            device.insert(String::from("path"), value);
+            let devices = player
+                .entry("devices")
+                .or_insert_with(|| toml::Value::Array(Vec::new()));
+            if let toml::Value::Array(devices) = devices {
+                devices.push(toml::Value::Table(device));
+            }
+        } else {
+            player.insert(key.to_string(), value);
+        }
+    }
+    if old.is_empty() {
+        return Ok(());
+    }
+    if table.contains_key("players") {
+        return Err(format!(
+            "the config has both [[players]] and {} at the top level; move them into the player \
+             they belong to",
+            old.join(", ")
+        )
+        .into());
+    }
+    log::warn!(
+        "The config has {} at the top level, which now belong to a player. They were moved into \
+         the only player; put them under [[players]] or run `melee-vpad setup` to update the file",
+        old.join(", ")
+    );
+    table.insert(
+        String::from("players"),
+        toml::Value::Array(vec![toml::Value::Table(player)]),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixture;
+
+    #[test]
+    fn defaults_survive_saving() {
+        let mut settings = Settings::default();
+        settings.players[0].profiles.push(Profile {
+            name: String::from("ultimate"),
+            rules: Some(Vec::new()),
+            ..Profile::default()
+        });
+        let saved = settings.to_toml().unwrap();
+        let loaded: Settings = toml::from_str(&saved).unwrap();
+        assert_eq!(loaded.to_toml().unwrap(), saved);
+        assert_eq!(loaded.rules.len(), settings.rules.len());
+        let profile = &loaded.players[0].profiles[0];
+        assert_eq!(profile.rules.as_ref().map(Vec::len), Some(0));
+    }
+
+    #[test]
+    fn chords_are_written_with_plus() {
+        let chord = Chord::try_from(String::from("KEY_LEFTCTRL + KEY_F12")).unwrap();
+        assert_eq!(chord, Chord(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_F12]));
+        assert_eq!(chord.to_string(), "KEY_LEFTCTRL+KEY_F12");
+        // turns the typing toggle off
+        assert_eq!(Chord::try_from(String::new()), Ok(Chord::default()));
+        assert!(Chord::try_from(String::from("KEY_NOPE")).is_err());
+    }
+
+    #[test]
+    fn pipes_default_to_one_per_port() {
+        let first = Player::default();
+        let second = Player {
+            port: 2,
+            ..Player::default()
+        };
+        assert_ne!(first.pipe_path(), second.pipe_path());
+        assert!(second.pipe_path().ends_with("Pipes/melee-vpad2"));
+        let custom = Player {
+            dolphin_pipe_path: Some(String::from("/tmp/pad")),
+            ..second
+        };
+        assert_eq!(custom.pipe_path(), Path::new("/tmp/pad"));
+    }
+
+    #[test]
+    fn old_configs_become_the_only_player() {
+        let settings = Settings::load(&fixture("old_config.toml")).unwrap();
+        assert_eq!(settings.players.len(), 1);
+        let player = &settings.players[0];
+        assert_eq!(player.devices.len(), 1);
+        assert_eq!(
+            player.devices[0].path.as_deref(),
+            Some("/dev/input/by-id/usb-CATEX_TECH._84EC-XRGB_CA2017090002-event-kbd")
+        );
+        assert_eq!(player.binds.a, Keys::from(EV_KEY::KEY_J));
+        assert_eq!(
+            player.binds.control_stick.downleft,
+            Keys::from(EV_KEY::KEY_Z)
+        );
+        assert!(player.binds.typing_toggle.0.is_empty());
+        assert_eq!(player.port, 1);
+    }
+
+    #[test]
+    fn old_tuning_is_dropped() {
+        let contents = fs::read_to_string(fixture("old_config.toml")).unwrap();
+        let mut value: toml::Value = toml::from_str(&contents).unwrap();
+        let table = value.as_table_mut().unwrap();
+        migrate(table).unwrap();
+        assert!(ANGLE_KEYS.iter().all(|key| !table.contains_key(*key)));
+        assert!(table.contains_key("mod1_trigger_mul"));
+    }
+
+    #[test]
+    fn old_keys_next_to_players_are_an_error() {
+        let mut contents = fs::read_to_string(fixture("old_config.toml")).unwrap();
+        contents.insert_str(0, "grab = true\n");
+        contents.push_str("\n[[players]]\nname = \"p2\"\n");
+        let e = Settings::parse(&contents).unwrap_err().to_string();
+        assert!(e.contains("grab, binds, keyboard_path"), "{}", e);
+    }
+
+    #[test]
+    fn current_configs_are_left_alone() {
+        let mut settings = Settings::default();
+        settings.players[0].grab = true;
+        settings.players.push(Player {
+            name: String::from("p2"),
+            port: 2,
+            ..Player::default()
+        });
+        let parsed = Settings::parse(&settings.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.players.len(), 2);
+        assert!(parsed.players[0].grab);
+        assert_eq!(parsed.players[1].name, "p2");
+    }
 }
@@ -1,7 +1,9 @@
 use crate::config::{DPad8Binds, DPadBinds};
+use crate::dpad::DPadState;
 use evdev_rs::enums::EV_KEY;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Dir8 {
     NW,
     N,
@@ -12,3 +14,38 @@ pub enum Dir8 {
     S,
     SE,
 }
+
+impl Dir8 {
+    /// The direction a dpad is pointing in, if any.
+    #[inline]
+    pub fn of(dpad: &DPadState) -> Option<Dir8> {
+        let x = (dpad.right() as i8) - (dpad.left() as i8);
+        let y = (dpad.down() as i8) - (dpad.up() as i8);
+        Some(match (x, y) {
+            (-1, -1) => Dir8::NW,
+            (0, -1) => Dir8::N,
+            (1, -1) => Dir8::NE,
+            (-1, 0) => Dir8::W,
+            (1, 0) => Dir8::E,
+            (-1, 1) => Dir8::SW,
+            (0, 1) => Dir8::S,
+            (1, 1) => Dir8::SE,
+            _ => return None,
+        })
+    }
+
+    /// The direction as (x, y), with right and down being positive like on the sticks.
+    #[inline]
+    pub fn vector(self) -> (i8, i8) {
+        match self {
+            Dir8::NW => (-1, -1),
+            Dir8::N => (0, -1),
+            Dir8::NE => (1, -1),
+            Dir8::W => (-1, 0),
+            Dir8::E => (1, 0),
+            Dir8::SW => (-1, 1),
+            Dir8::S => (0, 1),
+            Dir8::SE => (1, 1),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use crate::config::DeviceMatch;
+use evdev_rs::{
+    enums::{EventCode, EV_KEY},
+    Device, DeviceWrapper,
+};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+pub const INPUT_DIR: &str = "/dev/input";
+/// Every device we create has a phys starting with this, which is how we tell them apart.
+pub const PHYS_PREFIX: &str = "melee-vpad/";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    pub phys: String,
+    pub keyboard: bool,
+}
+
+impl DeviceInfo {
+    pub fn of(path: &Path, device: &Device) -> DeviceInfo {
+        DeviceInfo {
+            path: path.to_path_buf(),
+            name: device.name().unwrap_or_default().to_string(),
+            vendor: device.vendor_id(),
+            product: device.product_id(),
+            phys: device.phys().unwrap_or_default().to_string(),
+            keyboard: is_keyboard(device),
+        }
+    }
+
+    /// Whether this is one of the devices we created ourselves.
+    pub fn is_ours(&self) -> bool {
+        self.phys.starts_with(PHYS_PREFIX)
+    }
+}
+
+impl DeviceMatch {
+    /// `name` and `phys` match if they are contained in the device's name and phys. A `path` is
+    /// resolved first, so that `/dev/input/by-id` links work.
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        // mice, game pads and the like are only taken when asked for
+        if !info.keyboard && *self == DeviceMatch::default() {
+            return false;
+        }
+        let path = self.path.as_ref().is_none_or(|path| {
+            fs::canonicalize(path).is_ok_and(|p| Some(p) == fs::canonicalize(&info.path).ok())
+        });
+        path && self
+            .name
+            .as_ref()
+            .is_none_or(|n| info.name.contains(n.as_str()))
+            && self.vendor.is_none_or(|v| v == info.vendor)
+            && self.product.is_none_or(|p| p == info.product)
+            && self
+                .phys
+                .as_ref()
+                .is_none_or(|p| info.phys.contains(p.as_str()))
+    }
+}
+
+#[inline]
+fn is_event_node(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"event")
+}
+
+/// Lists the evdev nodes in `dir`.
+pub fn scan(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if is_event_node(&entry.file_name()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Whether the device has letter keys, which tells keyboards apart from mice, power buttons and
+/// the like.
+pub fn is_keyboard(device: &Device) -> bool {
+    [EV_KEY::KEY_A, EV_KEY::KEY_Z, EV_KEY::KEY_SPACE]
+        .iter()
+        .all(|&key| device.has_event_code(&EventCode::EV_KEY(key)))
+}
+
+pub fn probe(path: &Path) -> Result<(Device, DeviceInfo)> {
+    let device = Device::new_from_file(File::open(path)?)?;
+    let info = DeviceInfo::of(path, &device);
+    Ok((device, info))
+}
+
+/// Watches a directory for files that change, through inotify.
+pub struct Watcher {
+    dir: PathBuf,
+    fd: RawFd,
+    // which file names are of interest
+    filter: fn(&OsStr) -> bool,
+}
+
+impl Watcher {
+    /// Watches `dir` for evdev nodes that appear or become readable. udev creates the node before
+    /// it fixes up its permissions, so both matter.
+    pub fn new(dir: &Path) -> Result<Watcher> {
+        Watcher::with(dir, libc::IN_CREATE | libc::IN_ATTRIB, is_event_node)
+    }
+
+    /// Watches `dir` for the inotify events in `mask`, on files whose name passes `filter`.
+    pub fn with(dir: &Path, mask: u32, filter: fn(&OsStr) -> bool) -> Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cdir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(fd, cdir.as_ptr(), mask) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(Watcher {
+            dir: dir.to_path_buf(),
+            fd,
+            filter,
+        })
+    }
+
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Returns the files that changed since the last call, without blocking.
+    pub fn read(&self) -> Vec<PathBuf> {
+        const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut paths = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n <= 0 {
+                break;
+            }
+            let mut i = 0;
+            while i + HEADER <= n as usize {
+                let ev = unsafe {
+                    std::ptr::read_unaligned(buf[i..].as_ptr() as *const libc::inotify_event)
+                };
+                let name = &buf[i + HEADER..i + HEADER + ev.len as usize];
+                let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or_default());
+                if (self.filter)(name) {
+                    let path = self.dir.join(name);
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+                i += HEADER + ev.len as usize;
+            }
+        }
+        paths
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn touch(dir: &TempDir, name: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        File::create(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn scan_only_lists_event_nodes() {
+        let dir = TempDir::new();
+        for name in ["event2", "mouse0", "event10", "js0", "event0", "mice"].iter() {
+            touch(&dir, name);
+        }
+        fs::create_dir(dir.path().join("by-id")).unwrap();
+        let names: Vec<PathBuf> = ["event0", "event10", "event2"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        assert_eq!(scan(dir.path()).unwrap(), names);
+    }
+
+    #[test]
+    fn watcher_reports_new_event_nodes() {
+        let dir = TempDir::new();
+        let watcher = Watcher::new(dir.path()).unwrap();
+        assert!(watcher.read().is_empty());
+        touch(&dir, "mouse1");
+        let event = touch(&dir, "event3");
+        assert_eq!(watcher.read(), vec![event]);
+        assert!(watcher.read().is_empty());
+    }
+
+    #[test]
+    fn ours_are_told_apart_by_phys() {
+        let info = |name: &str, phys: &str| DeviceInfo {
+            name: name.to_string(),
+            phys: phys.to_string(),
+            ..DeviceInfo::default()
+        };
+        assert!(info("melee-vpad", "melee-vpad/port1").is_ours());
+        assert!(info(
+            "Keyboard (melee-vpad passthrough)",
+            "melee-vpad/passthrough"
+        )
+        .is_ours());
+        assert!(!info("melee-vpad", "usb-0000:00:14.0-1/input0").is_ours());
+    }
+
+    #[test]
+    fn empty_matches_only_take_keyboards() {
+        let m = DeviceMatch::default();
+        let mouse = DeviceInfo {
+            name: "USB Mouse".to_string(),
+            ..DeviceInfo::default()
+        };
+        assert!(!m.matches(&mouse));
+        assert!(m.matches(&DeviceInfo {
+            keyboard: true,
+            ..mouse.clone()
+        }));
+        let named = DeviceMatch {
+            name: Some("Mouse".to_string()),
+            ..DeviceMatch::default()
+        };
+        assert!(named.matches(&mouse));
+    }
+
+    #[test]
+    fn paths_match_through_links() {
+        let dir = TempDir::new();
+        let event = touch(&dir, "event4");
+        touch(&dir, "event5");
+        let link = dir.path().join("usb-keyboard-event-kbd");
+        std::os::unix::fs::symlink(&event, &link).unwrap();
+        let m = DeviceMatch {
+            path: Some(link.to_string_lossy().into_owned()),
+            ..DeviceMatch::default()
+        };
+        let info = |name: &str| DeviceInfo {
+            path: dir.path().join(name),
+            ..DeviceInfo::default()
+        };
+        assert!(m.matches(&info("event4")));
+        assert!(!m.matches(&info("event5")));
+    }
+}
@@ -1,4 +1,5 @@
 use super::dir8::Dir8;
+use crate::config::{GateShape, SocdMode};
 use fixed::types::I1F7;
 use modular_bitfield::bitfield;
 
@@ -11,65 +12,59 @@ pub struct JoyStickState {
 
 impl JoyStickState {
     #[inline]
-    pub fn update_x(&mut self, mul: Option<I1F7>) {
+    pub fn update_x(&mut self) {
         let cx = (self.dpad.right() as i8) - (self.dpad.left() as i8);
-        if let Some(mul) = mul {
-            self.x = I1F7::saturating_from_num(cx).saturating_mul(mul);
-        } else {
-            self.x = I1F7::saturating_from_num(cx);
-        }
+        self.x = I1F7::saturating_from_num(cx);
     }
 
     #[inline]
-    pub fn update_y(&mut self, mul: Option<I1F7>) {
+    pub fn update_y(&mut self) {
         let cy = (self.dpad.down() as i8) - (self.dpad.up() as i8);
-        if let Some(mul) = mul {
-            self.y = I1F7::saturating_from_num(cy).saturating_mul(mul);
-        } else {
-            self.y = I1F7::saturating_from_num(cy);
-        }
+        self.y = I1F7::saturating_from_num(cy);
     }
+}
 
+// where the diagonal notches of a GameCube gate are
+const GC_GATE_DIAGONAL: f32 = 0.7;
+
+impl GateShape {
+    /// Pulls a stick position that lies outside the gate back onto its rim.
     #[inline]
-    pub fn step(&mut self, x_incr: I1F7, y_incr: I1F7) {
-        let vx = (self.dpad.right() as i8) - (self.dpad.left() as i8);
-        let vy = (self.dpad.down() as i8) - (self.dpad.up() as i8);
-        let (x0, y0) = (self.x, self.y);
-        let (mut x1, mut y1) = (I1F7::saturating_from_num(vx), I1F7::saturating_from_num(vy));
+    pub fn clamp(self, (x, y): (I1F7, I1F7)) -> (I1F7, I1F7) {
+        let (x, y) = (x.to_num::<f32>(), y.to_num::<f32>());
+        let scale = self.scale(x, y);
+        (
+            I1F7::saturating_from_num(x * scale),
+            I1F7::saturating_from_num(y * scale),
+        )
+    }
 
-        match (x0.is_zero(), y0.is_zero()) {
-            (true, true) => {}
-            (true, false) => {
-                x1 = self.x.saturating_add(x1 * x_incr);
-            }
-            (false, true) => {
-                y1 = self.y.saturating_add(y1 * y_incr);
+    // how much (x, y) has to be scaled by to fit inside the gate
+    #[inline]
+    fn scale(self, x: f32, y: f32) -> f32 {
+        match self {
+            GateShape::Square => 1.0,
+            GateShape::Circle => {
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    1.0 / r
+                } else {
+                    1.0
+                }
             }
-            (false, false) => {
-                x1 = self.x.saturating_add(x1 * x_incr);
-                y1 = self.y.saturating_add(y1 * y_incr);
+            GateShape::Octagon => {
+                // fold into the first octant, where the edge runs from (1, 0) to (d, d)
+                let (x, y) = (x.abs(), y.abs());
+                let (a, b) = if x > y { (x, y) } else { (y, x) };
+                let d = GC_GATE_DIAGONAL;
+                let edge = d * a + (1.0 - d) * b;
+                if edge > d {
+                    d / edge
+                } else {
+                    1.0
+                }
             }
         }
-
-        self.x = x1;
-        self.y = y1;
-
-        // match (vx, vy) {
-        //     (0, 0) => {
-        //         self.x = I1F7::ZERO;
-        //         self.y = I1F7::ZERO;
-        //     }
-        //     (0, _) => {
-        //         self.x = I1F7::ZERO;
-        //         self.y = y1;
-        //     }
-        //     (_, 0) => {
-        //         self.x = x1;
-        //         self.y = I1F7::ZERO;
-        //     }
-        //     _ => {
-        //     }
-        // }
     }
 }
 
@@ -88,46 +83,77 @@ pub struct DPadState {
 
 impl DPadState {
     #[inline]
-    pub fn on_up(&mut self, value: bool) {
-        self.set_up(value);
+    pub fn on_up(&mut self, value: bool, mode: SocdMode) {
         self.set_up_held(value);
-        if value {
-            self.set_down(false);
-        } else {
-            self.set_down(self.down_held());
-        }
+        let (up, down) = resolve(mode, true, value, self.down_held(), self.down());
+        self.set_up(up);
+        self.set_down(down);
     }
 
     #[inline]
-    pub fn on_down(&mut self, value: bool) {
-        self.set_down(value);
+    pub fn on_down(&mut self, value: bool, mode: SocdMode) {
         self.set_down_held(value);
-        if value {
-            self.set_up(false);
-        } else {
-            self.set_up(self.up_held());
-        }
+        let (down, up) = resolve(mode, false, value, self.up_held(), self.up());
+        self.set_up(up);
+        self.set_down(down);
     }
 
     #[inline]
-    pub fn on_left(&mut self, value: bool) {
-        self.set_left(value);
+    pub fn on_left(&mut self, value: bool, mode: SocdMode) {
         self.set_left_held(value);
-        if value {
-            self.set_right(false);
-        } else {
-            self.set_right(self.right_held());
-        }
+        let (left, right) = resolve(mode, true, value, self.right_held(), self.right());
+        self.set_left(left);
+        self.set_right(right);
     }
 
     #[inline]
-    pub fn on_right(&mut self, value: bool) {
-        self.set_right(value);
+    pub fn on_right(&mut self, value: bool, mode: SocdMode) {
         self.set_right_held(value);
-        if value {
-            self.set_left(false);
-        } else {
-            self.set_left(self.left_held());
+        let (right, left) = resolve(mode, false, value, self.left_held(), self.left());
+        self.set_left(left);
+        self.set_right(right);
+    }
+}
+
+// Resolves one axis after one of its directions was pressed or released. `negative` says whether
+// that direction is up/left. Returns the new outputs for (this direction, the opposite one).
+#[inline]
+fn resolve(
+    mode: SocdMode,
+    negative: bool,
+    value: bool,
+    other_held: bool,
+    other: bool,
+) -> (bool, bool) {
+    match mode {
+        SocdMode::LastInput => {
+            if value {
+                (true, false)
+            } else {
+                (false, other_held)
+            }
+        }
+        SocdMode::LastInputNoReactivation => {
+            if value {
+                (true, false)
+            } else {
+                (false, other)
+            }
+        }
+        SocdMode::Neutral => (value && !other_held, other_held && !value),
+        SocdMode::FirstInput => {
+            if value {
+                (!other, other)
+            } else {
+                (false, other_held)
+            }
+        }
+        SocdMode::Negative | SocdMode::Positive => {
+            if (mode == SocdMode::Negative) == negative {
+                (value, other_held && !value)
+            } else {
+                (value && !other_held, other_held)
+            }
         }
     }
 }
@@ -0,0 +1,307 @@
+use crate::config::Settings;
+use crate::gc::{GcPad, GcPadSink};
+use crate::record::{self, LogEntry, LogEvent};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Result, Write};
+use std::path::Path;
+
+pub const SIGNATURE: &[u8; 4] = b"DTM\x1A";
+pub const HEADER_SIZE: usize = 0x100;
+pub const MELEE_NTSC_1_02: &[u8; 6] = b"GALE01";
+
+// the GameCube's CPU runs at 486MHz and Melee at 60 frames per second
+const TICKS_PER_FRAME: u64 = 486_000_000 / 60;
+pub const FRAME_US: u64 = 1_000_000 / 60;
+
+/// The parts of a Dolphin movie header we care about. Everything else is left zeroed, which tells
+/// Dolphin to use its own settings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DtmHeader {
+    pub game_id: [u8; 6],
+    /// Bit N is set when GameCube port N+1 has a controller
+    pub controllers: u8,
+    pub vi_count: u64,
+    pub input_count: u64,
+    pub rerecords: u32,
+    pub author: String,
+    pub tick_count: u64,
+}
+
+impl DtmHeader {
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut h = [0u8; HEADER_SIZE];
+        h[0x00..0x04].copy_from_slice(SIGNATURE);
+        h[0x04..0x0A].copy_from_slice(&self.game_id);
+        h[0x0B] = self.controllers & 0x0F;
+        h[0x0D..0x15].copy_from_slice(&self.vi_count.to_le_bytes());
+        h[0x15..0x1D].copy_from_slice(&self.input_count.to_le_bytes());
+        h[0x2D..0x31].copy_from_slice(&self.rerecords.to_le_bytes());
+        let author = self.author.as_bytes();
+        let n = author.len().min(32);
+        h[0x31..0x31 + n].copy_from_slice(&author[..n]);
+        h[0xED..0xF5].copy_from_slice(&self.tick_count.to_le_bytes());
+        h
+    }
+
+    pub fn decode(h: &[u8; HEADER_SIZE]) -> Result<DtmHeader> {
+        if &h[0x00..0x04] != SIGNATURE {
+            return Err(invalid("not a Dolphin movie"));
+        }
+        if h[0x0A] != 0 {
+            return Err(invalid("Wii movies are not supported"));
+        }
+        let u64_at = |at: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&h[at..at + 8]);
+            u64::from_le_bytes(b)
+        };
+        let mut rerecords = [0u8; 4];
+        rerecords.copy_from_slice(&h[0x2D..0x31]);
+        let author = &h[0x31..0x51];
+        let author_len = author.iter().position(|&b| b == 0).unwrap_or(author.len());
+        let mut game_id = [0u8; 6];
+        game_id.copy_from_slice(&h[0x04..0x0A]);
+        Ok(DtmHeader {
+            game_id,
+            controllers: h[0x0B] & 0x0F,
+            vi_count: u64_at(0x0D),
+            input_count: u64_at(0x15),
+            rerecords: u32::from_le_bytes(rerecords),
+            author: String::from_utf8_lossy(&author[..author_len]).into_owned(),
+            tick_count: u64_at(0xED),
+        })
+    }
+
+    /// The GameCube ports that have a controller, starting at 0.
+    pub fn ports(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..4).filter(move |port| self.controllers & (1 << port) != 0)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// One frame of controller data per connected port, in port order.
+pub type Frame = Vec<GcPad>;
+
+pub fn write<P: AsRef<Path>>(path: P, header: &DtmHeader, frames: &[Frame]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&header.encode())?;
+    for frame in frames {
+        for pad in frame {
+            w.write_all(&pad.encode())?;
+        }
+    }
+    w.flush()
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<(DtmHeader, Vec<Frame>)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if data.len() < HEADER_SIZE {
+        return Err(invalid("truncated header"));
+    }
+    let mut h = [0u8; HEADER_SIZE];
+    h.copy_from_slice(&data[..HEADER_SIZE]);
+    let header = DtmHeader::decode(&h)?;
+
+    let ports = header.ports().count();
+    let frame_size = ports * GcPad::SIZE;
+    let body = &data[HEADER_SIZE..];
+    if frame_size == 0 || body.len() % frame_size != 0 {
+        return Err(invalid(
+            "controller data does not match the connected ports",
+        ));
+    }
+    let frames = body
+        .chunks(frame_size)
+        .map(|frame| frame.chunks(GcPad::SIZE).map(GcPad::decode).collect())
+        .collect();
+    Ok((header, frames))
+}
+
+/// The GameCube port, starting at 0, that pad `pad` of a recording is on. That is the port of its
+/// player, so pads without a player or on a port past the fourth have none.
+pub fn port(settings: &Settings, pad: usize) -> Option<usize> {
+    let player = settings.players.get(pad)?;
+    (player.port as usize)
+        .checked_sub(1)
+        .filter(|&port| port < 4)
+}
+
+/// Samples what every GameCube port outputs once per frame, starting when the recording did. Each
+/// frame has all four ports, and ports without a pad stay neutral.
+pub fn sample(settings: &Settings, entries: &[LogEntry]) -> Vec<Frame> {
+    let sinks: Vec<GcPadSink> = (0..4).map(|_| GcPadSink::default()).collect();
+    let end = entries.last().map_or(0, |entry| entry.t);
+
+    let mut frames = Vec::new();
+    let mut entries = entries.iter().peekable();
+    let mut t = 0;
+    while t <= end {
+        while let Some(entry) = entries.next_if(|entry| entry.t <= t) {
+            if let (Some(port), LogEvent::Output(ev)) = (port(settings, entry.pad), entry.event) {
+                ev.write_to(&sinks[port]);
+            }
+        }
+        frames.push(sinks.iter().map(GcPadSink::get).collect::<Frame>());
+        t += FRAME_US;
+    }
+    frames
+}
+
+/// Turns a recording into a movie. Each pad goes to the port of its player, like `play_dtm` reads
+/// them back.
+pub fn export<P: AsRef<Path>, Q: AsRef<Path>>(
+    settings: &Settings,
+    log: P,
+    out: Q,
+) -> Result<DtmHeader> {
+    let entries = record::read_log(log)?;
+    let controllers = entries
+        .iter()
+        .filter_map(|entry| port(settings, entry.pad))
+        .fold(0u8, |controllers, port| controllers | 1 << port);
+    let frames: Vec<Frame> = sample(settings, &entries)
+        .into_iter()
+        .map(|frame| {
+            (0..4)
+                .filter(|port| controllers & (1 << port) != 0)
+                .map(|port| frame[port])
+                .collect()
+        })
+        .collect();
+    let pads = controllers.count_ones() as u64;
+
+    let n = frames.len() as u64;
+    let header = DtmHeader {
+        game_id: *MELEE_NTSC_1_02,
+        controllers,
+        vi_count: n,
+        input_count: n * pads,
+        rerecords: 0,
+        author: String::from("melee-vpad"),
+        tick_count: n * TICKS_PER_FRAME,
+    };
+    write(out, &header, &frames)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Player;
+    use crate::gc;
+    use crate::testing::{fixture, TempDir};
+
+    // pad 0 on port 2 and pad 1 on port 4, with pad 2 in the recording left without a player
+    fn two_players() -> Settings {
+        let mut settings = Settings::default();
+        settings.players[0].port = 2;
+        settings.players.push(Player {
+            port: 4,
+            ..Player::default()
+        });
+        settings
+    }
+
+    #[test]
+    fn movies_survive_a_round_trip() {
+        let (header, frames) = read(fixture("two_ports.dtm")).unwrap();
+        assert_eq!(
+            header,
+            DtmHeader {
+                game_id: *MELEE_NTSC_1_02,
+                controllers: 0b0101,
+                vi_count: 3,
+                input_count: 6,
+                rerecords: 7,
+                author: String::from("fixture"),
+                tick_count: 3 * TICKS_PER_FRAME,
+            }
+        );
+        assert_eq!(header.ports().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].iter().all(|pad| *pad == GcPad::default()));
+        assert!(frames[1][0].held(gc::A));
+        assert_eq!(frames[1][0].stick_x, 255);
+        assert_eq!(frames[1][1].trigger_l, 140);
+        assert!(frames[2][1].held(gc::L));
+        assert_eq!((frames[2][1].c_stick_x, frames[2][1].c_stick_y), (0, 200));
+
+        let dir = TempDir::new();
+        let copy = dir.path().join("copy.dtm");
+        write(&copy, &header, &frames).unwrap();
+        assert_eq!(read(&copy).unwrap(), (header, frames));
+        assert_eq!(
+            std::fs::read(&copy).unwrap(),
+            std::fs::read(fixture("two_ports.dtm")).unwrap()
+        );
+    }
+
+    #[test]
+    fn pads_survive_a_round_trip() {
+        let pad = GcPad {
+            buttons: gc::CONNECTED | gc::START | gc::Z | gc::DPAD_LEFT | gc::R,
+            trigger_l: 37,
+            trigger_r: 255,
+            stick_x: 1,
+            stick_y: 254,
+            c_stick_x: 128,
+            c_stick_y: 0,
+        };
+        assert_eq!(GcPad::decode(&pad.encode()), pad);
+    }
+
+    fn rejects(name: &str, why: &str) {
+        let e = read(fixture(name)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), why);
+    }
+
+    #[test]
+    fn bad_movies_are_rejected() {
+        rejects("bad_signature.dtm", "not a Dolphin movie");
+        rejects("wii.dtm", "Wii movies are not supported");
+        rejects(
+            "short_frame.dtm",
+            "controller data does not match the connected ports",
+        );
+    }
+
+    #[test]
+    fn samples_go_to_the_port_of_their_player() {
+        let settings = two_players();
+        let frames = sample(
+            &settings,
+            &record::read_log(fixture("two_players.log")).unwrap(),
+        );
+        assert_eq!(frames.len(), 4);
+        let pressed = &frames[2];
+        assert!(pressed[1].held(gc::A));
+        assert_eq!(pressed[1].stick_x, 255);
+        assert_eq!(pressed[3].trigger_l, 128);
+        assert_eq!(pressed[0], GcPad::default());
+        assert_eq!(pressed[2], GcPad::default());
+    }
+
+    #[test]
+    fn exported_movies_use_the_ports_of_the_players() {
+        let dir = TempDir::new();
+        let movie = dir.path().join("two_players.dtm");
+        let header = export(&two_players(), fixture("two_players.log"), &movie).unwrap();
+        assert_eq!(header.controllers, 0b1010);
+        assert_eq!(header.input_count, 2 * header.vi_count);
+
+        let (read_header, frames) = read(&movie).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(header.ports().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[1], vec![GcPad::default(); 2]);
+        assert!(frames[2][0].held(gc::A));
+        assert_eq!(frames[2][1].trigger_l, 128);
+        assert!(!frames[3][0].held(gc::A));
+        assert_eq!(frames[3][0].stick_x, gc::STICK_CENTER);
+    }
+}
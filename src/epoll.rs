@@ -0,0 +1,118 @@
+use std::io::{self, Result};
+use std::os::unix::io::RawFd;
+
+/// An epoll instance that reports which of its file descriptors became readable.
+pub struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    pub fn new() -> Result<Epoll> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    /// Watches `fd` for input. `wait` hands back `fd` itself once it is readable.
+    pub fn add(&self, fd: RawFd) -> Result<()> {
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: fd as u64,
+        };
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut ev) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until at least one file descriptor is readable, and returns the readable ones.
+    /// Returns nothing when interrupted by a signal.
+    pub fn wait(&self) -> Result<Vec<RawFd>> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 32];
+        let n = unsafe { libc::epoll_wait(self.fd, events.as_mut_ptr(), events.len() as i32, -1) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+        Ok(events[..n as usize]
+            .iter()
+            .map(|ev| ev.u64 as RawFd)
+            .collect())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// An eventfd that another thread can set off, to wake up an `Epoll` that watches it.
+pub struct Notify {
+    fd: RawFd,
+}
+
+impl Notify {
+    pub fn new() -> Result<Notify> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Notify { fd })
+    }
+
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    pub fn notify(&self) {
+        let one = 1u64.to_ne_bytes();
+        unsafe { libc::write(self.fd, one.as_ptr() as *const libc::c_void, one.len()) };
+    }
+
+    /// Whether `notify` was called since the last time, without blocking.
+    pub fn take(&self) -> bool {
+        let mut count = [0u8; 8];
+        let n = unsafe {
+            libc::read(
+                self.fd,
+                count.as_mut_ptr() as *mut libc::c_void,
+                count.len(),
+            )
+        };
+        n == count.len() as isize
+    }
+}
+
+impl Drop for Notify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn notify_wakes_up_a_wait() {
+        let notify = Arc::new(Notify::new().unwrap());
+        let epoll = Epoll::new().unwrap();
+        epoll.add(notify.fd()).unwrap();
+        assert!(!notify.take());
+
+        let other = notify.clone();
+        thread::spawn(move || other.notify());
+        assert_eq!(epoll.wait().unwrap(), vec![notify.fd()]);
+        assert!(notify.take());
+        assert!(!notify.take());
+    }
+}
@@ -0,0 +1,206 @@
+use crate::output::OutputSink;
+use evdev_rs::enums::{EV_ABS, EV_KEY};
+use fixed::types::I1F7;
+use std::cell::Cell;
+
+/// The state of a GameCube controller as the console sees it, laid out like a frame of a Dolphin
+/// movie.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GcPad {
+    pub buttons: u16,
+    pub trigger_l: u8,
+    pub trigger_r: u8,
+    pub stick_x: u8,
+    pub stick_y: u8,
+    pub c_stick_x: u8,
+    pub c_stick_y: u8,
+}
+
+pub const START: u16 = 1 << 0;
+pub const A: u16 = 1 << 1;
+pub const B: u16 = 1 << 2;
+pub const X: u16 = 1 << 3;
+pub const Y: u16 = 1 << 4;
+pub const Z: u16 = 1 << 5;
+pub const DPAD_UP: u16 = 1 << 6;
+pub const DPAD_DOWN: u16 = 1 << 7;
+pub const DPAD_LEFT: u16 = 1 << 8;
+pub const DPAD_RIGHT: u16 = 1 << 9;
+pub const L: u16 = 1 << 10;
+pub const R: u16 = 1 << 11;
+pub const CONNECTED: u16 = 1 << 14;
+
+pub const STICK_CENTER: u8 = 128;
+
+const BUTTON_KEYS: [(u16, EV_KEY); 11] = [
+    (START, EV_KEY::BTN_START),
+    (A, EV_KEY::BTN_EAST),
+    (B, EV_KEY::BTN_SOUTH),
+    (X, EV_KEY::BTN_NORTH),
+    (Y, EV_KEY::BTN_TL),
+    (Z, EV_KEY::BTN_Z),
+    (DPAD_UP, EV_KEY::BTN_DPAD_UP),
+    (DPAD_DOWN, EV_KEY::BTN_DPAD_DOWN),
+    (DPAD_LEFT, EV_KEY::BTN_DPAD_LEFT),
+    (DPAD_RIGHT, EV_KEY::BTN_DPAD_RIGHT),
+    (R, EV_KEY::BTN_TR),
+];
+
+impl Default for GcPad {
+    fn default() -> GcPad {
+        GcPad {
+            buttons: CONNECTED,
+            trigger_l: 0,
+            trigger_r: 0,
+            stick_x: STICK_CENTER,
+            stick_y: STICK_CENTER,
+            c_stick_x: STICK_CENTER,
+            c_stick_y: STICK_CENTER,
+        }
+    }
+}
+
+impl GcPad {
+    pub const SIZE: usize = 8;
+
+    pub fn encode(&self) -> [u8; GcPad::SIZE] {
+        let [b0, b1] = self.buttons.to_le_bytes();
+        [
+            b0,
+            b1,
+            self.trigger_l,
+            self.trigger_r,
+            self.stick_x,
+            self.stick_y,
+            self.c_stick_x,
+            self.c_stick_y,
+        ]
+    }
+
+    pub fn decode(bytes: &[u8]) -> GcPad {
+        GcPad {
+            buttons: u16::from_le_bytes([bytes[0], bytes[1]]),
+            trigger_l: bytes[2],
+            trigger_r: bytes[3],
+            stick_x: bytes[4],
+            stick_y: bytes[5],
+            c_stick_x: bytes[6],
+            c_stick_y: bytes[7],
+        }
+    }
+
+    #[inline]
+    pub fn held(&self, button: u16) -> bool {
+        self.buttons & button != 0
+    }
+
+    #[inline]
+    fn set(&mut self, button: u16, value: bool) {
+        if value {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+
+    /// Applies one output of the virtual pad.
+    pub fn apply_key(&mut self, key: EV_KEY, value: bool) {
+        if let Some(&(button, _)) = BUTTON_KEYS.iter().find(|(_, k)| *k == key) {
+            self.set(button, value);
+            if button == R {
+                self.trigger_r = if value { u8::MAX } else { 0 };
+            }
+        }
+    }
+
+    pub fn apply_joystick(&mut self, axis: EV_ABS, value: I1F7) {
+        // the console's sticks have up as positive
+        match axis {
+            EV_ABS::ABS_X => self.stick_x = axis_to_byte(value),
+            EV_ABS::ABS_Y => self.stick_y = axis_to_byte(value.saturating_neg()),
+            EV_ABS::ABS_RX => self.c_stick_x = axis_to_byte(value),
+            EV_ABS::ABS_RY => self.c_stick_y = axis_to_byte(value.saturating_neg()),
+            _ => {}
+        }
+    }
+
+    pub fn apply_trigger(&mut self, axis: EV_ABS, depth: I1F7) {
+        if axis == EV_ABS::ABS_Z {
+            self.trigger_l = (depth.to_bits().max(0) as u8).saturating_mul(2);
+            self.set(L, depth == I1F7::MAX);
+        }
+    }
+
+    /// Writes this state to a sink as one report. The digital L button has no output of its own,
+    /// it is implied by a fully pressed trigger.
+    pub fn write_to<O: OutputSink + ?Sized>(&self, out: &O) {
+        for &(button, key) in BUTTON_KEYS.iter() {
+            out.key(key, self.held(button));
+        }
+        out.joystick(EV_ABS::ABS_X, byte_to_axis(self.stick_x));
+        out.joystick(EV_ABS::ABS_Y, byte_to_axis(self.stick_y).saturating_neg());
+        out.joystick(EV_ABS::ABS_RX, byte_to_axis(self.c_stick_x));
+        out.joystick(
+            EV_ABS::ABS_RY,
+            byte_to_axis(self.c_stick_y).saturating_neg(),
+        );
+        let depth = if self.held(L) {
+            I1F7::MAX
+        } else {
+            I1F7::from_bits((self.trigger_l / 2) as i8)
+        };
+        out.trigger(EV_ABS::ABS_Z, depth);
+        out.sync();
+    }
+}
+
+// I1F7 has exactly 256 steps, so this is lossless
+#[inline]
+fn axis_to_byte(value: I1F7) -> u8 {
+    (value.to_bits() as i16 + STICK_CENTER as i16) as u8
+}
+
+#[inline]
+fn byte_to_axis(byte: u8) -> I1F7 {
+    I1F7::from_bits((byte as i16 - STICK_CENTER as i16) as i8)
+}
+
+/// An output sink that tracks the state of a GameCube controller. Outputs only take effect once
+/// they are synced.
+#[derive(Default)]
+pub struct GcPadSink {
+    pending: Cell<GcPad>,
+    current: Cell<GcPad>,
+}
+
+impl GcPadSink {
+    #[inline]
+    pub fn get(&self) -> GcPad {
+        self.current.get()
+    }
+
+    #[inline]
+    fn modify<F: FnOnce(&mut GcPad)>(&self, f: F) {
+        let mut pad = self.pending.get();
+        f(&mut pad);
+        self.pending.set(pad);
+    }
+}
+
+impl OutputSink for GcPadSink {
+    fn sync(&self) {
+        self.current.set(self.pending.get());
+    }
+
+    fn key(&self, key: EV_KEY, value: bool) {
+        self.modify(|pad| pad.apply_key(key, value));
+    }
+
+    fn joystick(&self, key: EV_ABS, value: I1F7) {
+        self.modify(|pad| pad.apply_joystick(key, value));
+    }
+
+    fn trigger(&self, key: EV_ABS, depth: I1F7) {
+        self.modify(|pad| pad.apply_trigger(key, depth));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::discovery::{self, DeviceInfo};
+use crate::keyset::{KeySet, KEY_CNT};
+use evdev_rs::{
+    enums::{int_to_ev_key, EventCode, EV_KEY, EV_SYN},
+    Device, DeviceWrapper, GrabMode, InputEvent, ReadFlag, ReadStatus, TimeVal, UInputDevice,
+};
+use std::io::{ErrorKind, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+/// The parts of an evdev device that a keyboard is read through, so that tests can hand in a fake
+/// one.
+pub trait Source {
+    fn fd(&self) -> RawFd;
+    fn name(&self) -> Option<&str>;
+    fn has_event_code(&self, code: &EventCode) -> bool;
+    fn has_event_pending(&self) -> bool;
+    fn next_event(&self, flags: ReadFlag) -> Result<(ReadStatus, InputEvent)>;
+    /// Whether `key` is down, as far as the device knows
+    fn held(&self, key: EV_KEY) -> bool;
+    fn grab(&mut self, mode: GrabMode) -> Result<()>;
+
+    /// Whether any key is down, as far as the device knows
+    fn any_held(&self) -> bool {
+        (0..KEY_CNT as u32)
+            .filter_map(int_to_ev_key)
+            .any(|key| self.held(key))
+    }
+}
+
+impl Source for Device {
+    #[inline]
+    fn fd(&self) -> RawFd {
+        self.file().as_raw_fd()
+    }
+
+    #[inline]
+    fn name(&self) -> Option<&str> {
+        DeviceWrapper::name(self)
+    }
+
+    #[inline]
+    fn has_event_code(&self, code: &EventCode) -> bool {
+        DeviceWrapper::has_event_code(self, code)
+    }
+
+    #[inline]
+    fn has_event_pending(&self) -> bool {
+        Device::has_event_pending(self)
+    }
+
+    #[inline]
+    fn next_event(&self, flags: ReadFlag) -> Result<(ReadStatus, InputEvent)> {
+        Device::next_event(self, flags)
+    }
+
+    #[inline]
+    fn held(&self, key: EV_KEY) -> bool {
+        self.event_value(&EventCode::EV_KEY(key)) == Some(1)
+    }
+
+    #[inline]
+    fn grab(&mut self, mode: GrabMode) -> Result<()> {
+        Device::grab(self, mode)
+    }
+}
+
+pub struct Keyboard {
+    pub device: Box<dyn Source>,
+    pub info: DeviceInfo,
+    // keys held on this keyboard alone
+    pub keys: KeySet,
+    // key events of the report that is still coming in
+    pub report: Vec<InputEvent>,
+    // re-emits keys that aren't bound to anything while the keyboard is grabbed
+    passthrough: Option<UInputDevice>,
+    passthrough_held: KeySet,
+    // only keyboards opened with `grab` have somewhere to pass unbound keys through
+    grabbable: bool,
+    grabbed: bool,
+    // a grab that waits for every key to be released, see `set_grab`
+    grab_pending: bool,
+}
+
+impl Keyboard {
+    pub fn new(mut device: Device, info: DeviceInfo, grab: bool) -> Result<Keyboard> {
+        // the realtime clock can jump, which would throw off latencies
+        device.set_clock_id(libc::CLOCK_MONOTONIC)?;
+        let passthrough = if grab {
+            let name = DeviceWrapper::name(&device)
+                .unwrap_or("keyboard")
+                .to_string();
+            let phys = device.phys().unwrap_or_default().to_string();
+            // so that it isn't picked up as a keyboard itself
+            device.set_name(&format!("{} (melee-vpad passthrough)", name));
+            device.set_phys(&format!("{}passthrough", discovery::PHYS_PREFIX));
+            let uinput = UInputDevice::create_from_device(&device);
+            device.set_name(&name);
+            device.set_phys(&phys);
+            let uinput = uinput?;
+            log::info!(
+                "Passing unbound keys of {:?} through {:?}",
+                name,
+                uinput.devnode()
+            );
+            Some(uinput)
+        } else {
+            None
+        };
+        let mut kbd = Keyboard::with_source(Box::new(device), info);
+        kbd.passthrough = passthrough;
+        kbd.grabbable = grab;
+        kbd.set_grab(grab)?;
+        Ok(kbd)
+    }
+
+    /// A keyboard that is read from `device`, without passing anything through.
+    pub fn with_source(device: Box<dyn Source>, info: DeviceInfo) -> Keyboard {
+        Keyboard {
+            device,
+            info,
+            keys: KeySet::default(),
+            report: Vec::new(),
+            passthrough: None,
+            passthrough_held: KeySet::default(),
+            grabbable: false,
+            grabbed: false,
+            grab_pending: false,
+        }
+    }
+
+    /// Grabs or releases the keyboard. Grabbing only does anything when the keyboard was opened
+    /// with `grab`, since otherwise there is nothing to pass unbound keys through. While any key
+    /// is held the grab waits for `grab_if_released`, since the desktop would never see those keys
+    /// come up.
+    pub fn set_grab(&mut self, grab: bool) -> Result<()> {
+        let grab = grab && self.grabbable;
+        if !grab {
+            self.grab_pending = false;
+        }
+        if grab == self.grabbed {
+            return Ok(());
+        }
+        if grab {
+            if self.device.any_held() {
+                if !self.grab_pending {
+                    log::info!(
+                        "Grabbing keyboard {:?} once its keys are released",
+                        self.device.name()
+                    );
+                }
+                self.grab_pending = true;
+                return Ok(());
+            }
+            self.grab_pending = false;
+            self.device.grab(GrabMode::Grab)?;
+            log::info!("Grabbed keyboard {:?}", self.device.name());
+        } else {
+            self.release_passthrough();
+            self.device.grab(GrabMode::Ungrab)?;
+            log::info!("Released keyboard {:?}", self.device.name());
+        }
+        self.grabbed = grab;
+        Ok(())
+    }
+
+    /// Lets `set_grab` grab a keyboard that has nothing to pass keys through.
+    #[cfg(test)]
+    pub fn grabbable(mut self) -> Keyboard {
+        self.grabbable = true;
+        self
+    }
+
+    /// Does a grab that was waiting for the keys to be released, if they are.
+    #[inline]
+    pub fn grab_if_released(&mut self) -> Result<()> {
+        if self.grab_pending && !self.device.any_held() {
+            self.set_grab(true)?;
+        }
+        Ok(())
+    }
+
+    /// Catches up after the kernel dropped events that weren't read in time. The report that was
+    /// coming in is thrown away, and `keys` is read back from the device.
+    pub fn resync(&mut self) -> Result<()> {
+        self.report.clear();
+        // libevdev works out the current state by itself; the events leading there aren't needed
+        loop {
+            match self.device.next_event(ReadFlag::SYNC) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.keys.clear();
+        for key in (0..KEY_CNT as u32).filter_map(int_to_ev_key) {
+            if self.device.held(key) {
+                self.keys.set(key, true);
+            }
+        }
+        // release whatever was let go of on the passthrough device too
+        let released: Vec<_> = self
+            .passthrough_held
+            .iter()
+            .filter(|&key| !self.keys.contains(key))
+            .collect();
+        for key in released {
+            self.passthrough(&InputEvent {
+                time: now(),
+                event_code: EventCode::EV_KEY(key),
+                value: 0,
+            });
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn passthrough(&mut self, ev: &InputEvent) {
+        let uinput = match &self.passthrough {
+            Some(uinput) if self.grabbed => uinput,
+            _ => return,
+        };
+        if let EventCode::EV_KEY(key) = ev.event_code {
+            // don't send releases for keys that were pressed before the grab
+            if ev.value != 0 {
+                self.passthrough_held.set(key, true);
+            } else if self.passthrough_held.contains(key) {
+                self.passthrough_held.set(key, false);
+            } else {
+                return;
+            }
+        }
+        let _ = uinput.write_event(ev);
+        let _ = uinput.write_event(&InputEvent {
+            time: ev.time,
+            event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+            value: 0,
+        });
+    }
+
+    // releases every key still held on the passthrough device, so nothing is stuck down once the
+    // desktop sees the real keyboard again
+    fn release_passthrough(&mut self) {
+        if let Some(uinput) = &self.passthrough {
+            let time = now();
+            for key in self.passthrough_held.iter() {
+                let _ = uinput.write_event(&InputEvent {
+                    time,
+                    event_code: EventCode::EV_KEY(key),
+                    value: 0,
+                });
+            }
+            let _ = uinput.write_event(&InputEvent {
+                time,
+                event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+                value: 0,
+            });
+        }
+        self.passthrough_held.clear();
+    }
+}
+
+impl Drop for Keyboard {
+    // hands the keyboard back to the desktop with nothing held. The device may already be gone,
+    // in which case there is nothing to ungrab.
+    fn drop(&mut self) {
+        let _ = self.set_grab(false);
+    }
+}
+
+/// The time on the monotonic clock, which keyboard events are stamped with.
+pub fn now() -> TimeVal {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    TimeVal::new(ts.tv_sec as i64, ts.tv_nsec as i64 / 1000)
+}
+
+/// How long ago `time` was.
+pub fn age(time: &TimeVal) -> Duration {
+    let now = now();
+    let us = (now.tv_sec - time.tv_sec) * 1_000_000 + (now.tv_usec - time.tv_usec);
+    Duration::from_micros(us.max(0) as u64)
+}
+
+/// A device that only has the events and keys it is told about.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct FakeSource {
+    // whether each one ends a gap of dropped events
+    events: std::rc::Rc<std::cell::RefCell<std::collections::VecDeque<(bool, InputEvent)>>>,
+    keys: std::rc::Rc<std::cell::Cell<KeySet>>,
+    grabbed: std::rc::Rc<std::cell::Cell<bool>>,
+}
+
+#[cfg(test)]
+impl FakeSource {
+    fn push(&self, dropped: bool, event_code: EventCode, value: i32) {
+        self.events.borrow_mut().push_back((
+            dropped,
+            InputEvent {
+                time: now(),
+                event_code,
+                value,
+            },
+        ));
+    }
+
+    fn set(&self, key: EV_KEY, value: i32) {
+        let mut keys = self.keys.get();
+        keys.set(key, value != 0);
+        self.keys.set(keys);
+    }
+
+    /// Sends a report with a single key event.
+    pub fn key(&self, key: EV_KEY, value: i32) {
+        self.set(key, value);
+        self.push(false, EventCode::EV_KEY(key), value);
+        self.push(false, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+    }
+
+    pub fn grabbed(&self) -> bool {
+        self.grabbed.get()
+    }
+
+    /// Changes `key` without an event for it, as if the kernel dropped it.
+    pub fn drop_key(&self, key: EV_KEY, value: i32) {
+        self.set(key, value);
+        self.push(true, EventCode::EV_SYN(EV_SYN::SYN_DROPPED), 0);
+    }
+}
+
+#[cfg(test)]
+impl Source for FakeSource {
+    fn fd(&self) -> RawFd {
+        -1
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("fake keyboard")
+    }
+
+    fn has_event_code(&self, code: &EventCode) -> bool {
+        matches!(code, EventCode::EV_KEY(_))
+    }
+
+    fn has_event_pending(&self) -> bool {
+        !self.events.borrow().is_empty()
+    }
+
+    fn next_event(&self, flags: ReadFlag) -> Result<(ReadStatus, InputEvent)> {
+        // the keys are always up to date, so there is never anything to catch up on
+        if flags.contains(ReadFlag::SYNC) {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        match self.events.borrow_mut().pop_front() {
+            Some((true, ev)) => Ok((ReadStatus::Sync, ev)),
+            Some((false, ev)) => Ok((ReadStatus::Success, ev)),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn held(&self, key: EV_KEY) -> bool {
+        self.keys.get().contains(key)
+    }
+
+    fn grab(&mut self, mode: GrabMode) -> Result<()> {
+        self.grabbed.set(matches!(mode, GrabMode::Grab));
+        Ok(())
+    }
+}
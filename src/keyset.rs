@@ -0,0 +1,51 @@
+use evdev_rs::enums::{int_to_ev_key, EV_KEY};
+
+pub const KEY_CNT: usize = 0x300;
+
+/// The set of keys currently held down on an input device.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub struct KeySet {
+    bits: [u64; KEY_CNT / 64],
+}
+
+impl KeySet {
+    #[inline]
+    pub fn contains(&self, key: EV_KEY) -> bool {
+        let i = key as usize;
+        i < KEY_CNT && self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    #[inline]
+    pub fn set(&mut self, key: EV_KEY, held: bool) {
+        let i = key as usize;
+        if i >= KEY_CNT {
+            return;
+        }
+        if held {
+            self.bits[i / 64] |= 1 << (i % 64);
+        } else {
+            self.bits[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    #[inline]
+    pub fn contains_all(&self, keys: &[EV_KEY]) -> bool {
+        !keys.is_empty() && keys.iter().all(|&k| self.contains(k))
+    }
+
+    pub fn clear(&mut self) {
+        self.bits = [0; KEY_CNT / 64];
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = EV_KEY> + '_ {
+        (0..KEY_CNT)
+            .filter(move |&i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+            .filter_map(|i| int_to_ev_key(i as u32))
+    }
+}
+
+impl std::fmt::Debug for KeySet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
@@ -1,51 +1,154 @@
 #[allow(non_snake_case)]
 use env_logger;
-use evdev_rs::{enums::EventCode, Device, ReadFlag};
 use std::error::Error;
-use std::fs::File;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::Arc;
 
+mod angles;
+mod cli;
 mod config;
 mod dir8;
+mod discovery;
 mod dpad;
+mod dtm;
+mod epoll;
+mod gc;
+mod keyboard;
+mod keyset;
+mod output;
+mod pad;
+mod pipe;
+mod record;
+mod replay;
+mod rules;
+mod setup;
+mod signals;
+mod slp;
 mod state;
+#[cfg(test)]
+mod testing;
+mod tui;
+mod validate;
+mod verify;
 mod vjoy;
-use crate::config::Settings;
-use crate::state::*;
-use crate::vjoy::*;
+use crate::cli::Command;
+use crate::config::{Settings, CONFIG_FILE};
+use crate::discovery::{Watcher, INPUT_DIR};
+use crate::epoll::{Epoll, Notify};
+use crate::keyboard::Source;
+use crate::pad::Pad;
+use crate::record::Recorder;
+use crate::signals::Signals;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    // the dashboard owns the terminal, so it shows the log in its debug console instead
+    match command {
+        Command::Run { tui: true, .. } => cursive::logger::init(),
+        _ => env_logger::init(),
+    }
     let settings = Settings::new()?;
 
-    let kbd = {
-        let path = settings.keyboard_path.clone();
-        let file = File::open(path).expect("Could not open keyboard device");
-        Device::new_from_file(file).expect("Could not create keyboard device")
+    match command {
+        Command::Run { record, tui } => run(settings, record.as_deref(), tui),
+        Command::Check => validate::report(&settings),
+        Command::Setup => setup::setup(settings),
+        Command::Replay { log, source } => replay::replay(&settings, &log, source),
+        Command::Dtm { log, movie } => {
+            let header = dtm::export(&settings, &log, &movie)?;
+            log::info!("Wrote {} frames to {:?}", header.vi_count, movie);
+            Ok(())
+        }
+        Command::Play { movie } => replay::play_dtm(&settings, &movie),
+        Command::Verify { log, replay } => verify::verify(&settings, &log, &replay),
+    }
+}
+
+fn run(mut settings: Settings, record: Option<&Path>, tui: bool) -> Result<(), Box<dyn Error>> {
+    validate::ensure(&validate::check(&settings))?;
+    let rec = match record {
+        Some(path) => {
+            log::info!("Recording to {:?}", path);
+            Some(Recorder::create(path)?)
+        }
+        None => None,
     };
 
-    let vjoy = VJoy::new(&settings)?;
+    let mut pads = (0..settings.players.len())
+        .map(|player| Pad::new(player, &settings, rec.clone()))
+        .collect::<Result<Vec<Pad>, _>>()?;
+    // only after the outputs, since opening a Dolphin pipe waits for Dolphin and Ctrl-C has to
+    // work until then; and before the dashboard starts its threads
+    let signals = Signals::new(&[libc::SIGINT, libc::SIGTERM])?;
+    // set off when it's time to shut down
+    let quit = Arc::new(Notify::new()?);
+    let tui = if tui {
+        let names = settings.players.iter().map(|p| p.name.clone()).collect();
+        let tui = tui::spawn(names, quit.clone());
+        for pad in pads.iter_mut() {
+            pad.tui = Some(tui.tx.clone());
+        }
+        Some(tui)
+    } else {
+        None
+    };
+
+    // watch before scanning, so that nothing plugged in between the two is missed
+    let input_dir = Path::new(INPUT_DIR);
+    let hotplug = Watcher::new(input_dir)?;
+    for path in discovery::scan(input_dir)? {
+        pad::attach(&mut pads, &path, &settings);
+    }
+    for pad in pads.iter().filter(|pad| pad.kbds.is_empty()) {
+        if settings.players[pad.player].devices.is_empty() {
+            log::warn!(
+                "No input devices configured for {:?}, run `melee-vpad setup`",
+                settings.players[pad.player].name
+            );
+            continue;
+        }
+        log::warn!(
+            "No input devices found for {:?}, waiting for one to be plugged in",
+            settings.players[pad.player].name
+        );
+    }
+    for pad in pads.iter() {
+        let devices: Vec<&dyn Source> = pad.kbds.iter().map(|kbd| &*kbd.device).collect();
+        let diags = validate::check_keyboards(&settings.players[pad.player], &devices);
+        validate::ensure(&diags)?;
+    }
 
-    let mut state = JoyState::default();
-    let binds_map = BindsMap::create(&settings.binds);
+    let config_path = Settings::path()?;
+    let config = Watcher::with(
+        config_path.parent().unwrap_or_else(|| Path::new("/")),
+        libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO,
+        |name| name == CONFIG_FILE,
+    )?;
 
     let poll_rate = settings.poll_rate;
 
     if poll_rate.as_millis() > 0 {
         log::debug!("using polling event loop");
-        loop {
+        while !stopping(&quit, &signals) {
             let t0 = std::time::Instant::now();
-            if !kbd.has_event_pending() {
-                // do nothing
-            } else if let Ok((_status, ev)) = kbd.next_event(ReadFlag::NORMAL) {
-                match ev.event_code {
-                    EventCode::EV_KEY(key) if !(ev.value > 1) => {
-                        let value = ev.value != 0;
-                        if let Some(update) = binds_map.lookup_key(key, value) {
-                            update.run(&mut state, &vjoy, &settings);
-                        }
-                    }
-                    _ => {}
-                }
+            for pad in pads.iter_mut() {
+                read_events(pad, &settings);
+            }
+            for path in hotplug.read() {
+                pad::attach(&mut pads, &path, &settings);
+            }
+            if !config.read().is_empty() {
+                reload(&config_path, &mut settings, &mut pads);
+            }
+            if let Some(rec) = &rec {
+                rec.tick();
             }
             let dt = t0.elapsed();
             if dt < poll_rate {
@@ -53,19 +156,163 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
     } else {
-        log::debug!("using blocking event loop");
-        while let Ok((_status, ev)) = kbd.next_event(ReadFlag::BLOCKING) {
-            match ev.event_code {
-                EventCode::EV_KEY(key) if !(ev.value > 1) => {
-                    let value = ev.value != 0;
-                    if let Some(update) = binds_map.lookup_key(key, value) {
-                        update.run(&mut state, &vjoy, &settings);
+        log::debug!("using epoll event loop");
+        let extra = [hotplug.fd(), config.fd(), quit.fd(), signals.fd()];
+        let mut epoll = watch(&pads, &extra)?;
+        'run: loop {
+            // whether keyboards came or went, which closes or reuses file descriptors
+            let mut changed = false;
+            for fd in epoll.wait()? {
+                if fd == quit.fd() || fd == signals.fd() {
+                    if stopping(&quit, &signals) {
+                        break 'run;
+                    }
+                } else if fd == hotplug.fd() {
+                    for path in hotplug.read() {
+                        changed |= pad::attach(&mut pads, &path, &settings);
+                    }
+                } else if fd == config.fd() {
+                    if !config.read().is_empty() {
+                        reload(&config_path, &mut settings, &mut pads);
+                        changed = true;
                     }
+                } else if let Some((p, i)) = find(&pads, fd) {
+                    changed |= !read_keyboard(&mut pads[p], i, &settings);
                 }
-                _ => {}
+            }
+            if let Some(rec) = &rec {
+                rec.tick();
+            }
+            if changed {
+                epoll = watch(&pads, &extra)?;
             }
         }
     }
 
+    log::info!("Shutting down");
+    for pad in pads.iter() {
+        pad.out.neutral();
+    }
+    // ungrabs the keyboards, and removes the virtual pads and passthrough devices
+    drop(pads);
+    if let Some(rec) = &rec {
+        rec.flush();
+    }
+    if let Some(tui) = tui {
+        tui.close();
+    }
     Ok(())
 }
+
+// whether the dashboard was quit or a signal asked us to stop
+fn stopping(quit: &Notify, signals: &Signals) -> bool {
+    if let Some(signal) = signals.take() {
+        log::info!("Caught signal {}", signal);
+        return true;
+    }
+    quit.take()
+}
+
+// an epoll instance for every keyboard of every pad, plus `extra`
+fn watch(pads: &[Pad], extra: &[RawFd]) -> std::io::Result<Epoll> {
+    let epoll = Epoll::new()?;
+    let kbds = pads.iter().flat_map(|pad| pad.kbds.iter());
+    for fd in kbds.map(|kbd| kbd.device.fd()).chain(extra.iter().copied()) {
+        epoll.add(fd)?;
+    }
+    Ok(epoll)
+}
+
+// the pad and index of the keyboard with file descriptor `fd`
+fn find(pads: &[Pad], fd: RawFd) -> Option<(usize, usize)> {
+    pads.iter().enumerate().find_map(|(p, pad)| {
+        pad.kbds
+            .iter()
+            .position(|kbd| kbd.device.fd() == fd)
+            .map(|i| (p, i))
+    })
+}
+
+// swaps in the config at `path` if it is valid, keeping the output devices as they are
+fn reload(path: &Path, settings: &mut Settings, pads: &mut [Pad]) {
+    let loaded =
+        Settings::load(path).and_then(|new| validate::ensure(&validate::check(&new)).map(|()| new));
+    let mut new = match loaded {
+        Ok(new) => new,
+        Err(e) => {
+            log::error!("Keeping the old config: {}", e);
+            return;
+        }
+    };
+    if new.players.len() != settings.players.len() {
+        log::error!("Keeping the old config: adding or removing players needs a restart");
+        return;
+    }
+    if new.poll_rate != settings.poll_rate {
+        log::warn!("Changing poll_rate needs a restart");
+        new.poll_rate = settings.poll_rate;
+    }
+    for (old, new) in settings.players.iter().zip(new.players.iter_mut()) {
+        if old.name != new.name
+            || old.port != new.port
+            || old.grab != new.grab
+            || old.output != new.output
+            || old.pipe_path() != new.pipe_path()
+        {
+            log::warn!(
+                "Changing the output or grab of {:?} needs a restart",
+                old.name
+            );
+            new.name = old.name.clone();
+            new.port = old.port;
+            new.grab = old.grab;
+            new.output = old.output;
+            new.dolphin_pipe_path = old.dolphin_pipe_path.clone();
+        }
+    }
+    *settings = new;
+
+    for pad in pads.iter_mut() {
+        pad.reload(settings);
+        // let go of keyboards that aren't wanted any more
+        let mut i = 0;
+        while i < pad.kbds.len() {
+            if pad.wants(&pad.kbds[i].info, settings) {
+                i += 1;
+            } else {
+                pad.detach(i, settings);
+            }
+        }
+    }
+    match discovery::scan(Path::new(INPUT_DIR)) {
+        Ok(paths) => {
+            for path in paths {
+                pad::attach(pads, &path, settings);
+            }
+        }
+        Err(e) => log::error!("Could not look for new input devices: {}", e),
+    }
+    log::info!("Reloaded {:?}", path);
+}
+
+// reads every pending event from every keyboard, dropping the ones that have gone away
+fn read_events(pad: &mut Pad, settings: &Settings) {
+    let mut i = 0;
+    while i < pad.kbds.len() {
+        if read_keyboard(pad, i, settings) {
+            i += 1;
+        }
+    }
+}
+
+// reads every pending event from keyboard `i`, and returns false if it went away
+fn read_keyboard(pad: &mut Pad, i: usize, settings: &Settings) -> bool {
+    match pad.read_events(i, settings) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("Lost {:?}: {}", pad.kbds[i].info.path, e);
+            pad.detach(i, settings);
+            false
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::{OutputKind, Player};
+use crate::pipe::DolphinPipe;
+use crate::vjoy::VJoy;
+use evdev_rs::{
+    enums::{EV_ABS, EV_KEY},
+    TimeVal,
+};
+use fixed::types::I1F7;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+
+/// Something a pad can write gamepad outputs to. Writes are buffered by the sink until `sync` is
+/// called, which is done once per input report.
+pub trait OutputSink {
+    fn sync(&self);
+    fn key(&self, key: EV_KEY, value: bool);
+    fn joystick(&self, key: EV_ABS, value: I1F7);
+    fn trigger(&self, key: EV_ABS, depth: I1F7);
+
+    /// Sets the time of the input that the next outputs come from.
+    fn stamp(&self, _time: TimeVal) {}
+
+    /// Releases every button and centres both sticks.
+    fn neutral(&self) {
+        for &key in BUTTONS.iter() {
+            self.key(key, false);
+        }
+        self.joystick(EV_ABS::ABS_X, I1F7::ZERO);
+        self.joystick(EV_ABS::ABS_Y, I1F7::ZERO);
+        self.joystick(EV_ABS::ABS_RX, I1F7::ZERO);
+        self.joystick(EV_ABS::ABS_RY, I1F7::ZERO);
+        self.trigger(EV_ABS::ABS_Z, I1F7::ZERO);
+        self.sync();
+    }
+}
+
+pub const BUTTONS: [EV_KEY; 12] = [
+    EV_KEY::BTN_EAST,
+    EV_KEY::BTN_WEST,
+    EV_KEY::BTN_NORTH,
+    EV_KEY::BTN_SOUTH,
+    EV_KEY::BTN_Z,
+    EV_KEY::BTN_TL,
+    EV_KEY::BTN_TR,
+    EV_KEY::BTN_START,
+    EV_KEY::BTN_DPAD_UP,
+    EV_KEY::BTN_DPAD_DOWN,
+    EV_KEY::BTN_DPAD_LEFT,
+    EV_KEY::BTN_DPAD_RIGHT,
+];
+
+pub fn create(player: &Player) -> Result<Box<dyn OutputSink>, Box<dyn Error>> {
+    Ok(match player.output {
+        OutputKind::Uinput => Box::new(VJoy::new(player)?),
+        OutputKind::DolphinPipe => Box::new(DolphinPipe::new(player.pipe_path())?),
+    })
+}
+
+/// Passes outputs on to `inner`, dropping writes that don't change anything and syncs with nothing
+/// to commit, so that a report only carries what changed.
+pub struct Dedup {
+    inner: Box<dyn OutputSink>,
+    // the last value written for each button and axis
+    keys: RefCell<Vec<(EV_KEY, bool)>>,
+    axes: RefCell<Vec<(EV_ABS, I1F7)>>,
+    // whether anything was written since the last sync
+    dirty: Cell<bool>,
+}
+
+impl Dedup {
+    pub fn new(inner: Box<dyn OutputSink>) -> Dedup {
+        Dedup {
+            inner,
+            keys: RefCell::new(Vec::new()),
+            axes: RefCell::new(Vec::new()),
+            dirty: Cell::new(false),
+        }
+    }
+
+    // remembers `value` for `code`, and returns whether that changed anything
+    fn changed<C: PartialEq, V: PartialEq>(last: &RefCell<Vec<(C, V)>>, code: C, value: V) -> bool {
+        let mut last = last.borrow_mut();
+        match last.iter_mut().find(|(c, _)| *c == code) {
+            Some((_, v)) if *v == value => false,
+            Some((_, v)) => {
+                *v = value;
+                true
+            }
+            None => {
+                last.push((code, value));
+                true
+            }
+        }
+    }
+}
+
+impl OutputSink for Dedup {
+    fn sync(&self) {
+        if self.dirty.replace(false) {
+            self.inner.sync();
+        }
+    }
+
+    fn key(&self, key: EV_KEY, value: bool) {
+        if Dedup::changed(&self.keys, key, value) {
+            self.dirty.set(true);
+            self.inner.key(key, value);
+        }
+    }
+
+    fn joystick(&self, key: EV_ABS, value: I1F7) {
+        if Dedup::changed(&self.axes, key, value) {
+            self.dirty.set(true);
+            self.inner.joystick(key, value);
+        }
+    }
+
+    fn trigger(&self, key: EV_ABS, depth: I1F7) {
+        if Dedup::changed(&self.axes, key, depth) {
+            self.dirty.set(true);
+            self.inner.trigger(key, depth);
+        }
+    }
+
+    fn stamp(&self, time: TimeVal) {
+        self.inner.stamp(time);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputEvent {
+    Sync,
+    Key(EV_KEY, bool),
+    Joystick(EV_ABS, I1F7),
+    Trigger(EV_ABS, I1F7),
+}
+
+impl OutputEvent {
+    #[inline]
+    pub fn write_to<O: OutputSink + ?Sized>(self, out: &O) {
+        match self {
+            OutputEvent::Sync => out.sync(),
+            OutputEvent::Key(key, value) => out.key(key, value),
+            OutputEvent::Joystick(axis, value) => out.joystick(axis, value),
+            OutputEvent::Trigger(axis, depth) => out.trigger(axis, depth),
+        }
+    }
+}
+
+/// Keeps every output in memory instead of sending it anywhere. Clones share their events, so one
+/// can be handed out and read back from the other.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RecordingSink {
+    pub events: std::rc::Rc<RefCell<Vec<OutputEvent>>>,
+}
+
+#[cfg(test)]
+impl RecordingSink {
+    pub fn take(&self) -> Vec<OutputEvent> {
+        self.events.replace(Vec::new())
+    }
+}
+
+#[cfg(test)]
+impl OutputSink for RecordingSink {
+    fn sync(&self) {
+        self.events.borrow_mut().push(OutputEvent::Sync);
+    }
+
+    fn key(&self, key: EV_KEY, value: bool) {
+        self.events.borrow_mut().push(OutputEvent::Key(key, value));
+    }
+
+    fn joystick(&self, key: EV_ABS, value: I1F7) {
+        self.events
+            .borrow_mut()
+            .push(OutputEvent::Joystick(key, value));
+    }
+
+    fn trigger(&self, key: EV_ABS, depth: I1F7) {
+        self.events
+            .borrow_mut()
+            .push(OutputEvent::Trigger(key, depth));
+    }
+}
@@ -0,0 +1,548 @@
+use crate::config::{Chord, Player, Settings};
+use crate::discovery::{self, DeviceInfo};
+use crate::keyboard::{self, Keyboard};
+use crate::keyset::KeySet;
+use crate::output::{self, Dedup, OutputSink};
+use crate::record::{Recorder, Tee};
+use crate::state::{BindsMap, BindsState, JoyState};
+use crate::tui::TuiEvent;
+use crossbeam::channel::Sender;
+use evdev_rs::{
+    enums::{EventCode, EV_KEY, EV_SYN},
+    Device, ReadFlag, ReadStatus, TimeVal,
+};
+use std::cell::Cell;
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+/// One virtual gamepad, together with the keyboards that drive it.
+pub struct Pad {
+    // index into `Settings::players`
+    pub player: usize,
+    pub kbds: Vec<Keyboard>,
+    pub out: Box<dyn OutputSink>,
+    pub state: JoyState,
+    pub binds_map: BindsMap,
+    binds_state: BindsState,
+    // keys held on any of the keyboards
+    keys: KeySet,
+    typing: bool,
+    // 0 when no profile is on, otherwise one past its index in `Player::profiles`
+    profile: usize,
+    // the settings with that profile applied
+    profile_settings: Option<Settings>,
+    rec: Option<Rc<Recorder>>,
+    pub tui: Option<Sender<TuiEvent>>,
+}
+
+impl Pad {
+    pub fn new(
+        player: usize,
+        settings: &Settings,
+        rec: Option<Rc<Recorder>>,
+    ) -> Result<Pad, Box<dyn Error>> {
+        let out = output::create(&settings.players[player])?;
+        Ok(Pad::with_output(player, settings, out, rec))
+    }
+
+    /// A pad that sends its outputs to `out` instead of the device its player asks for.
+    pub fn with_output(
+        player: usize,
+        settings: &Settings,
+        mut out: Box<dyn OutputSink>,
+        rec: Option<Rc<Recorder>>,
+    ) -> Pad {
+        let cfg = &settings.players[player];
+        if let Some(rec) = &rec {
+            out = Box::new(Tee {
+                inner: out,
+                rec: rec.clone(),
+                pad: player,
+                time: Cell::new(keyboard::now()),
+            });
+        }
+        Pad {
+            player,
+            kbds: Vec::new(),
+            // outermost, so that the recording only has what was really sent
+            out: Box::new(Dedup::new(out)),
+            state: JoyState::default(),
+            binds_map: BindsMap::create(&cfg.binds),
+            binds_state: BindsState::default(),
+            keys: KeySet::default(),
+            typing: false,
+            profile: 0,
+            profile_settings: None,
+            rec,
+            tui: None,
+        }
+    }
+
+    #[inline]
+    fn cfg<'a>(&self, settings: &'a Settings) -> &'a Player {
+        &settings.players[self.player]
+    }
+
+    // the settings with the active profile applied
+    #[inline]
+    fn active<'a>(&'a self, settings: &'a Settings) -> &'a Settings {
+        self.profile_settings.as_ref().unwrap_or(settings)
+    }
+
+    pub fn wants(&self, info: &DeviceInfo, settings: &Settings) -> bool {
+        self.cfg(settings).devices.iter().any(|m| m.matches(info))
+    }
+
+    pub fn attach(&mut self, device: Device, info: DeviceInfo, settings: &Settings) -> bool {
+        let path = info.path.clone();
+        let grab = self.cfg(settings).grab && !self.typing;
+        match Keyboard::new(device, info, grab) {
+            Ok(kbd) => {
+                log::info!("Attached {:?} to {:?}", kbd.info, self.cfg(settings).name);
+                self.kbds.push(kbd);
+                true
+            }
+            Err(e) => {
+                log::error!("Could not attach {:?}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    /// Detaches a keyboard that went away, releasing whatever it was holding.
+    pub fn detach(&mut self, i: usize, settings: &Settings) {
+        let kbd = self.kbds.remove(i);
+        log::info!("Detached {:?}", kbd.info);
+        for key in kbd.keys.iter() {
+            self.handle_key(key, 0, settings);
+        }
+        self.flush(keyboard::now());
+    }
+
+    /// Reads every event keyboard `i` has pending. Key events are held back until the
+    /// `SYN_REPORT` that ends their report, so that a report is always handled as a whole.
+    pub fn read_events(&mut self, i: usize, settings: &Settings) -> io::Result<()> {
+        while self.kbds[i].device.has_event_pending() {
+            let (status, ev) = self.kbds[i].device.next_event(ReadFlag::NORMAL)?;
+            if let ReadStatus::Sync = status {
+                self.resync(i, settings)?;
+                continue;
+            }
+            match ev.event_code {
+                EventCode::EV_KEY(_) => self.kbds[i].report.push(ev),
+                EventCode::EV_SYN(EV_SYN::SYN_REPORT) => self.handle_report(i, ev.time, settings),
+                _ => {}
+            }
+        }
+        if let Err(e) = self.kbds[i].grab_if_released() {
+            log::error!("Could not grab {:?}: {}", self.kbds[i].info.path, e);
+        }
+        Ok(())
+    }
+
+    // starts over from what keyboard `i` says is held, after it dropped events
+    fn resync(&mut self, i: usize, settings: &Settings) -> io::Result<()> {
+        log::warn!("{:?} dropped events, catching up", self.kbds[i].info.path);
+        self.kbds[i].resync()?;
+        let mut keys = KeySet::default();
+        for key in self.kbds.iter().flat_map(|kbd| kbd.keys.iter()) {
+            keys.set(key, true);
+        }
+        if let Some(rec) = &self.rec {
+            let changed = self.keys.iter().chain(keys.iter());
+            for key in changed.filter(|&key| self.keys.contains(key) != keys.contains(key)) {
+                rec.key(self.player, key, keys.contains(key) as i32);
+            }
+        }
+        self.keys = keys;
+        if !self.typing {
+            self.recompute(settings);
+        }
+        Ok(())
+    }
+
+    // `time` is when the report came in
+    fn handle_report(&mut self, i: usize, time: TimeVal, settings: &Settings) {
+        let mut report = std::mem::take(&mut self.kbds[i].report);
+        if report.is_empty() {
+            return;
+        }
+        for ev in report.iter() {
+            let key = match ev.event_code {
+                EventCode::EV_KEY(key) => key,
+                _ => continue,
+            };
+            if ev.value <= 1 {
+                self.kbds[i].keys.set(key, ev.value != 0);
+            }
+            if let Some(rec) = &self.rec {
+                rec.key(self.player, key, ev.value);
+            }
+            if self.handle_key(key, ev.value, settings) {
+                self.kbds[i].passthrough(ev);
+            }
+        }
+        self.flush(time);
+        if let Some(tui) = &self.tui {
+            // only now that the outputs are written, so that the latency includes them
+            for ev in report.iter().filter(|ev| ev.value <= 1) {
+                if let EventCode::EV_KEY(key) = ev.event_code {
+                    let action = if ev.value != 0 { "down" } else { "up" };
+                    let _ = tui.try_send(TuiEvent::Log {
+                        pad: self.player,
+                        text: format!("{:?} {}", key, action),
+                        latency: keyboard::age(&ev.time),
+                    });
+                }
+            }
+            let _ = tui.try_send(TuiEvent::State {
+                pad: self.player,
+                state: self.state,
+            });
+        }
+        // hand the buffer back, so that it doesn't have to grow again
+        report.clear();
+        self.kbds[i].report = report;
+    }
+
+    /// Handles a key event that didn't come from any of the keyboards, e.g. from a recording.
+    pub fn feed_key(&mut self, key: EV_KEY, raw: i32, settings: &Settings) {
+        self.handle_key(key, raw, settings);
+        self.flush(keyboard::now());
+    }
+
+    // returns whether the key should be passed through. Outputs are only written by `flush`.
+    #[inline]
+    fn handle_key(&mut self, key: EV_KEY, raw: i32, settings: &Settings) -> bool {
+        let value = raw != 0;
+        let repeat = raw > 1;
+        if !repeat {
+            let held = value || self.kbds.iter().any(|kbd| kbd.keys.contains(key));
+            // another keyboard is holding it too, so nothing changes
+            if held == self.keys.contains(key) {
+                return !self.typing && !self.binds_map.is_bound(key);
+            }
+            self.keys.set(key, held);
+        }
+
+        let toggle = &self.active(settings).players[self.player]
+            .binds
+            .typing_toggle;
+        if value && !repeat && toggle.0.contains(&key) && self.keys.contains_all(&toggle.0) {
+            self.toggle_typing(settings);
+            return false;
+        }
+        if self.typing {
+            return false;
+        }
+        let cycle = &self.cfg(settings).profile_cycle;
+        if value && !repeat && cycle.0.contains(&key) && self.keys.contains_all(&cycle.0) {
+            self.cycle_profile(settings);
+            return false;
+        }
+
+        if repeat {
+            return !self.binds_map.is_bound(key);
+        }
+
+        let Pad {
+            player,
+            state,
+            binds_map,
+            binds_state,
+            keys,
+            profile_settings,
+            rec,
+            ..
+        } = self;
+        let settings = profile_settings.as_ref().unwrap_or(settings);
+        let bound = binds_map.update(key, keys, binds_state, |update| {
+            if let Some(rec) = rec {
+                rec.update(*player, update);
+            }
+            update.run(state, settings);
+        });
+        !bound
+    }
+
+    fn toggle_typing(&mut self, settings: &Settings) {
+        self.typing = !self.typing;
+        if self.typing {
+            log::info!("Entering typing mode");
+            self.neutral();
+            self.binds_state = BindsState::default();
+            self.set_grab(false);
+        } else {
+            log::info!("Leaving typing mode");
+            // the toggle is still held, so this only grabs once it is released
+            self.set_grab(self.cfg(settings).grab);
+            let toggle = self.active(settings).players[self.player]
+                .binds
+                .typing_toggle
+                .clone();
+            self.rebuild(settings, &toggle);
+        }
+    }
+
+    /// Switches to the next profile. Every output is released first, and the keys that are held
+    /// are carried over into the new binds, except for the ones of the cycle chord.
+    fn cycle_profile(&mut self, settings: &Settings) {
+        let cfg = self.cfg(settings);
+        if cfg.profiles.is_empty() {
+            log::info!("{:?} has no profiles to switch to", cfg.name);
+            return;
+        }
+        self.profile = (self.profile + 1) % (cfg.profiles.len() + 1);
+        match self.profile {
+            0 => log::info!("{:?} switched back to no profile", cfg.name),
+            i => log::info!(
+                "{:?} switched to profile {:?}",
+                cfg.name,
+                cfg.profiles[i - 1].name
+            ),
+        }
+        self.apply_profile(settings);
+        self.rebuild(settings, &cfg.profile_cycle);
+    }
+
+    // works out the settings and binds of the active profile
+    fn apply_profile(&mut self, settings: &Settings) {
+        let profiles = &self.cfg(settings).profiles;
+        self.profile_settings = match self.profile {
+            0 => None,
+            i => Some(settings.with_profile(self.player, &profiles[i - 1])),
+        };
+        self.binds_map = BindsMap::create(&self.active(settings).players[self.player].binds);
+        self.binds_state = BindsState::default();
+    }
+
+    fn set_grab(&mut self, grab: bool) {
+        for kbd in self.kbds.iter_mut() {
+            if let Err(e) = kbd.set_grab(grab) {
+                log::error!("Could not (un)grab {:?}: {}", kbd.info.path, e);
+            }
+        }
+    }
+
+    /// Resets the pad and replays every key that is currently held, except for `skip`.
+    pub fn rebuild(&mut self, settings: &Settings, skip: &Chord) {
+        self.neutral();
+        let mut keys = self.keys;
+        for &key in skip.0.iter() {
+            keys.set(key, false);
+        }
+        let Pad {
+            state,
+            binds_map,
+            binds_state,
+            profile_settings,
+            ..
+        } = self;
+        let settings = profile_settings.as_ref().unwrap_or(settings);
+        binds_map.replay(&keys, binds_state, |update| update.run(state, settings));
+        self.flush(keyboard::now());
+    }
+
+    /// Writes whatever changed since the last sync as a single report, stamped with `time`.
+    fn flush(&mut self, time: TimeVal) {
+        self.out.stamp(time);
+        self.state.out.write_to(&*self.out);
+        self.out.sync();
+    }
+
+    // releases everything on the output
+    fn neutral(&mut self) {
+        self.state = JoyState::default();
+        self.out.stamp(keyboard::now());
+        self.out.neutral();
+    }
+
+    /// Switches to the binds of freshly loaded settings while keeping the output device. What is
+    /// held is worked out again under the new binds, and only the outputs that changed are sent.
+    pub fn reload(&mut self, settings: &Settings) {
+        // stay on the same profile if it is still there
+        let name = match (&self.profile_settings, self.profile) {
+            (Some(old), i) if i > 0 => Some(old.players[self.player].profiles[i - 1].name.clone()),
+            _ => None,
+        };
+        self.profile = name
+            .and_then(|name| {
+                self.cfg(settings)
+                    .profiles
+                    .iter()
+                    .position(|profile| profile.name == name)
+            })
+            .map_or(0, |i| i + 1);
+        self.apply_profile(settings);
+        if self.typing {
+            // leaving typing mode rebuilds everything anyway
+            return;
+        }
+        self.recompute(settings);
+    }
+
+    // works the state out again from the keys that are held, and sends whatever changed
+    fn recompute(&mut self, settings: &Settings) {
+        self.state = JoyState::default();
+        let settings = self.profile_settings.as_ref().unwrap_or(settings);
+        let state = &mut self.state;
+        self.binds_map
+            .replay(&self.keys, &mut self.binds_state, |update| {
+                update.run(state, settings)
+            });
+        self.flush(keyboard::now());
+    }
+}
+
+/// Hands the device at `path` to the first pad that wants it. Returns whether anyone took it.
+pub fn attach(pads: &mut [Pad], path: &Path, settings: &Settings) -> bool {
+    if pads
+        .iter()
+        .any(|pad| pad.kbds.iter().any(|kbd| kbd.info.path == path))
+    {
+        return false;
+    }
+    let (device, info) = match discovery::probe(path) {
+        Ok(probed) => probed,
+        Err(e) => {
+            log::debug!("Skipping {:?}: {}", path, e);
+            return false;
+        }
+    };
+    if info.is_ours() {
+        return false;
+    }
+    match pads.iter_mut().find(|pad| pad.wants(&info, settings)) {
+        Some(pad) => pad.attach(device, info, settings),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dpad::JoyStickState;
+    use crate::keyboard::FakeSource;
+    use crate::output::{OutputEvent, RecordingSink};
+    use crate::state::Outputs;
+    use evdev_rs::enums::EV_ABS;
+    use fixed::types::I1F7;
+
+    fn pad(settings: &Settings, kbds: &[&FakeSource]) -> (Pad, RecordingSink) {
+        let sink = RecordingSink::default();
+        let mut pad = Pad::with_output(0, settings, Box::new(sink.clone()), None);
+        for kbd in kbds {
+            let info = DeviceInfo::default();
+            pad.kbds
+                .push(Keyboard::with_source(Box::new((*kbd).clone()), info));
+        }
+        (pad, sink)
+    }
+
+    #[test]
+    fn dropped_events_go_back_to_what_is_held() {
+        let settings = Settings::default();
+        let kbd = FakeSource::default();
+        let (mut pad, sink) = pad(&settings, &[&kbd]);
+
+        kbd.key(EV_KEY::KEY_A, 1);
+        pad.read_events(0, &settings).unwrap();
+        assert!(pad.state.control_stick.dpad.left());
+        assert!(pad.state.out.control_stick.0 < I1F7::ZERO);
+        sink.take();
+
+        // the release never arrives
+        kbd.drop_key(EV_KEY::KEY_A, 0);
+        pad.read_events(0, &settings).unwrap();
+        assert_eq!(pad.state.control_stick, JoyStickState::default());
+        assert_eq!(pad.state.out, Outputs::default());
+        assert_eq!(
+            sink.take(),
+            vec![
+                OutputEvent::Joystick(EV_ABS::ABS_X, I1F7::ZERO),
+                OutputEvent::Sync
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_stay_held_while_another_keyboard_holds_them() {
+        let settings = Settings::default();
+        let (a, b, c) = (
+            FakeSource::default(),
+            FakeSource::default(),
+            FakeSource::default(),
+        );
+        let (mut pad, sink) = pad(&settings, &[&a, &b, &c]);
+
+        a.key(EV_KEY::KEY_J, 1);
+        b.key(EV_KEY::KEY_J, 1);
+        c.key(EV_KEY::KEY_J, 1);
+        for i in 0..3 {
+            pad.read_events(i, &settings).unwrap();
+        }
+        assert!(pad.state.out.btn.a());
+        sink.take();
+
+        a.key(EV_KEY::KEY_J, 0);
+        pad.read_events(0, &settings).unwrap();
+        pad.detach(1, &settings);
+        assert!(pad.state.out.btn.a());
+        assert!(sink.take().is_empty());
+
+        // what was keyboard c
+        c.key(EV_KEY::KEY_J, 0);
+        pad.read_events(1, &settings).unwrap();
+        assert!(!pad.state.out.btn.a());
+        assert_eq!(
+            sink.take(),
+            vec![OutputEvent::Key(EV_KEY::BTN_EAST, false), OutputEvent::Sync]
+        );
+    }
+
+    #[test]
+    fn leaving_typing_mode_grabs_once_the_toggle_is_released() {
+        let mut settings = Settings::default();
+        settings.players[0].grab = true;
+        let kbd = FakeSource::default();
+        let (mut pad, _) = pad(&settings, &[]);
+        let info = DeviceInfo::default();
+        pad.kbds
+            .push(Keyboard::with_source(Box::new(kbd.clone()), info).grabbable());
+
+        let toggle = [EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_F12];
+        for &value in [1, 0, 1].iter() {
+            for &key in toggle.iter() {
+                kbd.key(key, value);
+            }
+            pad.read_events(0, &settings).unwrap();
+        }
+        assert!(!pad.typing);
+        // the desktop has to see the toggle come up first
+        assert!(!kbd.grabbed());
+        kbd.key(EV_KEY::KEY_F12, 0);
+        pad.read_events(0, &settings).unwrap();
+        assert!(!kbd.grabbed());
+        kbd.key(EV_KEY::KEY_LEFTCTRL, 0);
+        pad.read_events(0, &settings).unwrap();
+        assert!(kbd.grabbed());
+    }
+
+    #[test]
+    fn chords_dont_fire_again_from_another_keyboard() {
+        let settings = Settings::default();
+        let (a, b) = (FakeSource::default(), FakeSource::default());
+        let (mut pad, _) = pad(&settings, &[&a, &b]);
+
+        a.key(EV_KEY::KEY_LEFTCTRL, 1);
+        a.key(EV_KEY::KEY_F12, 1);
+        pad.read_events(0, &settings).unwrap();
+        assert!(pad.typing);
+
+        b.key(EV_KEY::KEY_LEFTCTRL, 1);
+        pad.read_events(1, &settings).unwrap();
+        assert!(pad.typing);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::output::OutputSink;
+use evdev_rs::enums::{EV_ABS, EV_KEY};
+use fixed::types::I1F7;
+use std::cell::{Cell, RefCell};
+use std::ffi::CString;
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Result, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Writes Dolphin's pipe input protocol to a FIFO, so that stick coordinates reach the game as-is
+/// instead of going through Dolphin's evdev mapping. If Dolphin closes its end, whatever comes
+/// in is dropped until it opens the pipe again.
+pub struct DolphinPipe {
+    path: PathBuf,
+    // `None` while Dolphin isn't reading
+    pipe: RefCell<Option<File>>,
+    buf: RefCell<String>,
+    main: Stick,
+    c: Stick,
+}
+
+// where a stick is, and whether that has been sent yet
+#[derive(Default)]
+struct Stick {
+    x: Cell<I1F7>,
+    y: Cell<I1F7>,
+    moved: Cell<bool>,
+}
+
+impl DolphinPipe {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<DolphinPipe> {
+        let path = path.as_ref();
+        if !path.exists() {
+            let cpath = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            if unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // opening a FIFO for writing blocks until there is a reader on the other end
+        log::info!("Waiting for Dolphin to open {:?}", path);
+        let pipe = OpenOptions::new().write(true).open(path)?;
+        log::info!("Opened Dolphin pipe {:?}", path);
+        Ok(DolphinPipe {
+            path: path.to_path_buf(),
+            pipe: RefCell::new(Some(pipe)),
+            buf: RefCell::new(String::new()),
+            main: Stick::default(),
+            c: Stick::default(),
+        })
+    }
+
+    fn line(&self, args: fmt::Arguments) {
+        let mut buf = self.buf.borrow_mut();
+        let _ = buf.write_fmt(args);
+        buf.push('\n');
+    }
+
+    fn stick(&self, name: &str, stick: &Stick) {
+        if !stick.moved.replace(false) {
+            return;
+        }
+        // dolphin sticks go from 0 to 1 with 0.5 at the centre, and up is positive
+        let px = 0.5 + stick.x.get().to_num::<f32>() / 2.0;
+        let py = 0.5 - stick.y.get().to_num::<f32>() / 2.0;
+        self.line(format_args!("SET {} {} {}", name, px, py));
+    }
+
+    // opens the pipe again if Dolphin is back, without waiting for it
+    fn reopen(&self) -> Option<File> {
+        let pipe = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path)
+            .ok()?;
+        // only the open shouldn't block; writes should, like they did before
+        unsafe { libc::fcntl(pipe.as_raw_fd(), libc::F_SETFL, 0) };
+        log::info!("Reopened Dolphin pipe {:?}", self.path);
+        Some(pipe)
+    }
+}
+
+impl OutputSink for DolphinPipe {
+    #[inline]
+    fn sync(&self) {
+        self.stick("MAIN", &self.main);
+        self.stick("C", &self.c);
+        let mut buf = self.buf.borrow_mut();
+        if buf.is_empty() {
+            return;
+        }
+        let mut pipe = self.pipe.borrow_mut();
+        if pipe.is_none() {
+            *pipe = self.reopen();
+        }
+        if let Some(mut file) = pipe.as_ref() {
+            match file.write_all(buf.as_bytes()) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    log::warn!(
+                        "Dolphin closed {:?}, waiting for it to open it again",
+                        self.path
+                    );
+                    *pipe = None;
+                }
+                Err(e) => log::error!("Could not write to Dolphin pipe: {}", e),
+            }
+        }
+        buf.clear();
+    }
+
+    #[inline]
+    fn key(&self, key: EV_KEY, value: bool) {
+        if let Some(button) = button_name(key) {
+            let action = if value { "PRESS" } else { "RELEASE" };
+            self.line(format_args!("{} {}", action, button));
+        }
+    }
+
+    #[inline]
+    fn joystick(&self, key: EV_ABS, value: I1F7) {
+        let (stick, axis) = match key {
+            EV_ABS::ABS_X => (&self.main, &self.main.x),
+            EV_ABS::ABS_Y => (&self.main, &self.main.y),
+            EV_ABS::ABS_RX => (&self.c, &self.c.x),
+            EV_ABS::ABS_RY => (&self.c, &self.c.y),
+            _ => return,
+        };
+        // sent as one line per stick once the report is complete
+        axis.set(value);
+        stick.moved.set(true);
+    }
+
+    #[inline]
+    fn trigger(&self, key: EV_ABS, depth: I1F7) {
+        let trigger = match key {
+            EV_ABS::ABS_Z => "L",
+            EV_ABS::ABS_RZ => "R",
+            _ => return,
+        };
+        let depth = depth.to_num::<f32>().max(0.0);
+        self.line(format_args!("SET {} {}", trigger, depth));
+    }
+}
+
+fn button_name(key: EV_KEY) -> Option<&'static str> {
+    Some(match key {
+        EV_KEY::BTN_EAST => "A",
+        EV_KEY::BTN_SOUTH => "B",
+        EV_KEY::BTN_NORTH => "X",
+        EV_KEY::BTN_TL => "Y",
+        EV_KEY::BTN_Z => "Z",
+        EV_KEY::BTN_TR => "R",
+        EV_KEY::BTN_START => "START",
+        EV_KEY::BTN_DPAD_UP => "D_UP",
+        EV_KEY::BTN_DPAD_DOWN => "D_DOWN",
+        EV_KEY::BTN_DPAD_LEFT => "D_LEFT",
+        EV_KEY::BTN_DPAD_RIGHT => "D_RIGHT",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::io::{BufRead, BufReader, Read};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn writes_dolphin_pipe_commands() {
+        let dir = TempDir::new();
+        let path = dir.path().join("pipe");
+        // opening the write end waits for this one
+        let reader = {
+            let path = path.clone();
+            thread::spawn(move || {
+                while !path.exists() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                let mut lines = String::new();
+                File::open(&path)
+                    .unwrap()
+                    .read_to_string(&mut lines)
+                    .unwrap();
+                lines
+            })
+        };
+
+        let pipe = DolphinPipe::new(&path).unwrap();
+        pipe.key(EV_KEY::BTN_EAST, true);
+        pipe.key(EV_KEY::BTN_THUMBL, true);
+        pipe.joystick(EV_ABS::ABS_X, I1F7::from_num(-0.5));
+        pipe.joystick(EV_ABS::ABS_Y, I1F7::from_num(0.5));
+        pipe.trigger(EV_ABS::ABS_Z, I1F7::from_num(0.25));
+        pipe.sync();
+        pipe.sync();
+        pipe.key(EV_KEY::BTN_EAST, false);
+        pipe.sync();
+        drop(pipe);
+
+        assert_eq!(
+            reader.join().unwrap(),
+            "PRESS A\n\
+             SET L 0.25\n\
+             SET MAIN 0.25 0.25\n\
+             RELEASE A\n"
+        );
+    }
+
+    #[test]
+    fn reopens_the_pipe_when_dolphin_comes_back() {
+        let dir = TempDir::new();
+        let path = dir.path().join("pipe");
+        // reads a single line and closes its end again
+        let first = {
+            let path = path.clone();
+            thread::spawn(move || {
+                while !path.exists() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                let mut line = String::new();
+                BufReader::new(File::open(&path).unwrap())
+                    .read_line(&mut line)
+                    .unwrap();
+                line
+            })
+        };
+        let pipe = DolphinPipe::new(&path).unwrap();
+        pipe.key(EV_KEY::BTN_EAST, true);
+        pipe.sync();
+        assert_eq!(first.join().unwrap(), "PRESS A\n");
+
+        pipe.key(EV_KEY::BTN_SOUTH, true);
+        pipe.sync();
+        assert!(pipe.pipe.borrow().is_none());
+        pipe.key(EV_KEY::BTN_SOUTH, false);
+        pipe.sync();
+        assert!(pipe.pipe.borrow().is_none());
+
+        let second = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut lines = String::new();
+                File::open(&path)
+                    .unwrap()
+                    .read_to_string(&mut lines)
+                    .unwrap();
+                lines
+            })
+        };
+        // whatever comes in before it is back is dropped
+        while pipe.pipe.borrow().is_none() {
+            pipe.key(EV_KEY::BTN_EAST, false);
+            pipe.sync();
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(pipe);
+        assert_eq!(second.join().unwrap(), "RELEASE A\n");
+    }
+}
@@ -0,0 +1,303 @@
+use crate::keyboard;
+use crate::output::{OutputEvent, OutputSink};
+use crate::state::{StateUpdate, StateUpdateKind};
+use evdev_rs::{
+    enums::{int_to_ev_abs, int_to_ev_key, EV_ABS, EV_KEY},
+    TimeVal,
+};
+use fixed::types::I1F7;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Result, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub const LOG_HEADER: &str = "# melee-vpad log v2";
+// from before output syncs had their latency
+const LOG_HEADER_V1: &str = "# melee-vpad log v1";
+
+// how much of a recording is lost at most if we don't get to shut down properly
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+/// Writes a line per input event, state update and output event, each prefixed with the number of
+/// microseconds since recording started and the index of the pad:
+///
+/// ```text
+/// <us> <pad> K <key code> <value>     key event from a keyboard
+/// <us> <pad> U <StateUpdateKind> <0|1>
+/// <us> <pad> O K <key code> <0|1>     output button
+/// <us> <pad> O J <axis code> <bits>   output stick axis, as the raw bits of an I1F7
+/// <us> <pad> O T <axis code> <bits>   output trigger
+/// <us> <pad> O S <latency>            output sync, and how many microseconds after the input
+///                                     that led to it
+/// ```
+///
+/// Plain text is kept on purpose: a log can be read, grepped and diffed as it is, and lines are
+/// short enough that an hour of play comes to about 10 MB. Lines are buffered and written out
+/// at most every `FLUSH_EVERY` and on `flush`, so that a report rarely pays for a write.
+pub struct Recorder {
+    start: Instant,
+    w: RefCell<BufWriter<File>>,
+    flushed: Cell<Instant>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Rc<Recorder>> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "{}", LOG_HEADER)?;
+        let now = Instant::now();
+        Ok(Rc::new(Recorder {
+            start: now,
+            w: RefCell::new(w),
+            flushed: Cell::new(now),
+        }))
+    }
+
+    #[inline]
+    pub fn key(&self, pad: usize, key: EV_KEY, value: i32) {
+        self.line(pad, format_args!("K {} {}", key as u32, value));
+    }
+
+    #[inline]
+    pub fn update(&self, pad: usize, update: StateUpdate) {
+        self.line(
+            pad,
+            format_args!("U {:?} {}", update.kind, update.value as u8),
+        );
+    }
+
+    #[inline]
+    pub fn output(&self, pad: usize, ev: OutputEvent) {
+        match ev {
+            OutputEvent::Sync => self.line(pad, format_args!("O S")),
+            OutputEvent::Key(key, value) => {
+                self.line(pad, format_args!("O K {} {}", key as u32, value as u8))
+            }
+            OutputEvent::Joystick(axis, value) => {
+                self.line(pad, format_args!("O J {} {}", axis as u32, value.to_bits()))
+            }
+            OutputEvent::Trigger(axis, depth) => {
+                self.line(pad, format_args!("O T {} {}", axis as u32, depth.to_bits()))
+            }
+        }
+    }
+
+    #[inline]
+    pub fn sync(&self, pad: usize, latency: Duration) {
+        self.line(pad, format_args!("O S {}", latency.as_micros()));
+    }
+
+    /// Writes out what is buffered, if that hasn't happened for `FLUSH_EVERY`.
+    pub fn tick(&self) {
+        if self.flushed.get().elapsed() >= FLUSH_EVERY {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Err(e) = self.w.borrow_mut().flush() {
+            log::error!("Could not write recording: {}", e);
+        }
+        self.flushed.set(Instant::now());
+    }
+
+    fn line(&self, pad: usize, args: fmt::Arguments) {
+        let t = self.start.elapsed().as_micros();
+        let _ = writeln!(self.w.borrow_mut(), "{} {} {}", t, pad, args);
+    }
+}
+
+/// Passes outputs on to `inner`, recording each of them.
+pub struct Tee {
+    pub inner: Box<dyn OutputSink>,
+    pub rec: Rc<Recorder>,
+    pub pad: usize,
+    // the time of the input the outputs come from
+    pub time: Cell<TimeVal>,
+}
+
+impl OutputSink for Tee {
+    fn sync(&self) {
+        self.rec.sync(self.pad, keyboard::age(&self.time.get()));
+        self.inner.sync();
+    }
+
+    fn key(&self, key: EV_KEY, value: bool) {
+        self.rec.output(self.pad, OutputEvent::Key(key, value));
+        self.inner.key(key, value);
+    }
+
+    fn joystick(&self, key: EV_ABS, value: I1F7) {
+        self.rec.output(self.pad, OutputEvent::Joystick(key, value));
+        self.inner.joystick(key, value);
+    }
+
+    fn trigger(&self, key: EV_ABS, depth: I1F7) {
+        self.rec.output(self.pad, OutputEvent::Trigger(key, depth));
+        self.inner.trigger(key, depth);
+    }
+
+    fn stamp(&self, time: TimeVal) {
+        self.time.set(time);
+        self.inner.stamp(time);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LogEvent {
+    Key(EV_KEY, i32),
+    Update(StateUpdate),
+    Output(OutputEvent),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    /// microseconds since the recording started
+    pub t: u64,
+    pub pad: usize,
+    pub event: LogEvent,
+}
+
+/// Reads back a log written by `Recorder`, in this version or the one before.
+pub fn read_log<P: AsRef<Path>>(path: P) -> Result<Vec<LogEntry>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let version = match lines.next().transpose()?.as_deref() {
+        Some(LOG_HEADER) => 2,
+        Some(LOG_HEADER_V1) => 1,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a melee-vpad log, or one from a newer version",
+            ))
+        }
+    };
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let entry = parse_line(&line, version).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: could not parse {:?}", i + 2, line),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_line(line: &str, version: u32) -> Option<LogEntry> {
+    let mut words = line.split_whitespace();
+    let t = words.next()?.parse().ok()?;
+    let pad = words.next()?.parse().ok()?;
+    let mut next = || words.next();
+    let event = match next()? {
+        "K" => LogEvent::Key(int_to_ev_key(next()?.parse().ok()?)?, next()?.parse().ok()?),
+        "U" => LogEvent::Update(StateUpdate {
+            kind: StateUpdateKind::from_name(next()?)?,
+            value: next()? != "0",
+        }),
+        "O" => LogEvent::Output(match next()? {
+            "S" => {
+                // the latency is only there to be looked at
+                if version >= 2 {
+                    next()?.parse::<u64>().ok()?;
+                }
+                OutputEvent::Sync
+            }
+            "K" => OutputEvent::Key(int_to_ev_key(next()?.parse().ok()?)?, next()? != "0"),
+            "J" => OutputEvent::Joystick(
+                int_to_ev_abs(next()?.parse().ok()?)?,
+                I1F7::from_bits(next()?.parse().ok()?),
+            ),
+            "T" => OutputEvent::Trigger(
+                int_to_ev_abs(next()?.parse().ok()?)?,
+                I1F7::from_bits(next()?.parse().ok()?),
+            ),
+            _ => return None,
+        }),
+        _ => return None,
+    };
+    Some(LogEntry { t, pad, event })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fixture, TempDir};
+    use std::fs;
+
+    #[test]
+    fn logs_read_back_what_was_recorded() {
+        let dir = TempDir::new();
+        let path = dir.path().join("rec.log");
+        let rec = Recorder::create(&path).unwrap();
+        let update = StateUpdate {
+            kind: StateUpdateKind::ControlStickLeft,
+            value: true,
+        };
+        let outputs = [
+            OutputEvent::Key(EV_KEY::BTN_EAST, true),
+            OutputEvent::Joystick(EV_ABS::ABS_X, I1F7::from_num(-0.5)),
+            OutputEvent::Trigger(EV_ABS::ABS_Z, I1F7::MAX),
+        ];
+        rec.key(1, EV_KEY::KEY_A, 1);
+        rec.update(1, update);
+        for &ev in outputs.iter() {
+            rec.output(1, ev);
+        }
+        rec.sync(1, Duration::from_micros(250));
+        rec.flush();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# melee-vpad log v2\n"));
+        assert!(text.ends_with(" 1 O S 250\n"));
+
+        let events: Vec<LogEvent> = read_log(&path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                assert_eq!(entry.pad, 1);
+                entry.event
+            })
+            .collect();
+        let mut expected = vec![LogEvent::Key(EV_KEY::KEY_A, 1), LogEvent::Update(update)];
+        expected.extend(outputs.iter().copied().map(LogEvent::Output));
+        expected.push(LogEvent::Output(OutputEvent::Sync));
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn v1_logs_still_read() {
+        let entries = read_log(fixture("two_players.log")).unwrap();
+        assert_eq!(entries.len(), 12);
+        assert_eq!(
+            entries[4],
+            LogEntry {
+                t: 20000,
+                pad: 1,
+                event: LogEvent::Output(OutputEvent::Trigger(EV_ABS::ABS_Z, I1F7::from_bits(64))),
+            }
+        );
+        assert_eq!(entries[5].event, LogEvent::Output(OutputEvent::Sync));
+    }
+
+    #[test]
+    fn syncs_need_their_latency_from_v2_on() {
+        let dir = TempDir::new();
+        let path = dir.path().join("rec.log");
+        fs::write(&path, format!("{}\n0 0 O S 120\n5 0 O S\n", LOG_HEADER)).unwrap();
+        let e = read_log(&path).unwrap_err();
+        assert_eq!(e.to_string(), "line 3: could not parse \"5 0 O S\"");
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let e = read_log(fixture("old_config.toml")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::config::Settings;
+use crate::dtm;
+use crate::output::{self, Dedup, OutputSink};
+use crate::pad::Pad;
+use crate::record::{self, LogEvent};
+use crate::validate;
+use std::error::Error;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Feed the recorded key events through the current binds and layout
+    Keys,
+    /// Send the recorded outputs as they are
+    Outputs,
+}
+
+// gives whatever reads the pad a moment to notice it before anything happens
+const STARTUP_DELAY: Duration = Duration::from_secs(1);
+
+pub fn replay(settings: &Settings, log: &Path, source: Source) -> Result<(), Box<dyn Error>> {
+    if source == Source::Keys {
+        validate::ensure(&validate::check(settings))?;
+    }
+    let entries = record::read_log(log)?;
+    let mut pads = (0..settings.players.len())
+        .map(|player| Pad::new(player, settings, None))
+        .collect::<Result<Vec<Pad>, _>>()?;
+
+    log::info!(
+        "Replaying {} events from {:?} in {:?}",
+        entries.len(),
+        log,
+        STARTUP_DELAY
+    );
+    thread::sleep(STARTUP_DELAY);
+    let start = Instant::now();
+    for entry in entries.iter() {
+        let pad = match pads.get_mut(entry.pad) {
+            Some(pad) => pad,
+            None => continue,
+        };
+        match (source, entry.event) {
+            (Source::Keys, LogEvent::Key(..)) | (Source::Outputs, LogEvent::Output(_)) => {}
+            _ => continue,
+        }
+        let t = Duration::from_micros(entry.t);
+        let elapsed = start.elapsed();
+        if t > elapsed {
+            thread::sleep(t - elapsed);
+        }
+        match entry.event {
+            LogEvent::Key(key, value) => pad.feed_key(key, value, settings),
+            LogEvent::Output(ev) => ev.write_to(&*pad.out),
+            LogEvent::Update(_) => {}
+        }
+    }
+
+    for pad in pads.iter() {
+        pad.out.neutral();
+    }
+    log::info!("Replay finished");
+    Ok(())
+}
+
+/// Plays a Dolphin movie into the virtual pads one frame at a time. Every player gets the
+/// controller of the port they are configured for.
+pub fn play_dtm(settings: &Settings, movie: &Path) -> Result<(), Box<dyn Error>> {
+    let (header, frames) = dtm::read(movie)?;
+    let ports: Vec<usize> = header.ports().collect();
+    let mut outs: Vec<(usize, Box<dyn OutputSink>)> = Vec::new();
+    for player in settings.players.iter() {
+        match ports
+            .iter()
+            .position(|&port| port + 1 == player.port as usize)
+        {
+            Some(i) => outs.push((i, Box::new(Dedup::new(output::create(player)?)))),
+            None => log::warn!("{:?} has no controller in the movie", player.name),
+        }
+    }
+    if outs.is_empty() {
+        return Err("the movie has no controller on any configured port".into());
+    }
+
+    log::info!(
+        "Playing {} frames of {} from {:?} in {:?}",
+        frames.len(),
+        String::from_utf8_lossy(&header.game_id),
+        movie,
+        STARTUP_DELAY
+    );
+    thread::sleep(STARTUP_DELAY);
+    let start = Instant::now();
+    for (n, frame) in frames.iter().enumerate() {
+        let t = Duration::from_micros(n as u64 * dtm::FRAME_US);
+        let elapsed = start.elapsed();
+        if t > elapsed {
+            thread::sleep(t - elapsed);
+        }
+        for (i, out) in outs.iter() {
+            frame[*i].write_to(&**out);
+        }
+    }
+
+    for (_, out) in outs.iter() {
+        out.neutral();
+    }
+    log::info!("Movie finished");
+    Ok(())
+}
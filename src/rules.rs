@@ -0,0 +1,208 @@
+use crate::angles::mirror;
+use crate::config::{Condition, Modifier, Rule};
+use crate::dir8::Dir8;
+use crate::dpad::DPadState;
+use crate::state::{JoyState, Outputs};
+
+#[inline]
+fn points(dirs: &Option<Vec<Dir8>>, dpad: &DPadState) -> bool {
+    match dirs {
+        Some(dirs) => Dir8::of(dpad).is_some_and(|dir| dirs.contains(&dir)),
+        None => true,
+    }
+}
+
+impl Condition {
+    pub fn holds(&self, state: &JoyState) -> bool {
+        let modifiers = match &self.modifiers {
+            Some(modifiers) => Modifier::exactly(modifiers, state.m),
+            None => true,
+        };
+        modifiers
+            && self.buttons.iter().all(|b| b.held(state.btn))
+            && (self.any_buttons.is_empty() || self.any_buttons.iter().any(|b| b.held(state.btn)))
+            && !self.not_buttons.iter().any(|b| b.held(state.btn))
+            && points(&self.control_stick, &state.control_stick.dpad)
+            && points(&self.c_stick, &state.c_stick.dpad)
+            && points(&self.dpad, &state.dpad)
+    }
+}
+
+/// Applies every rule whose condition holds, in order, so that later rules win over earlier ones.
+pub fn apply(rules: &[Rule], state: &JoyState, o: &mut Outputs) {
+    for rule in rules.iter().filter(|rule| rule.when.holds(state)) {
+        let then = &rule.then;
+        if let Some(c) = &then.control_stick {
+            o.control_stick = mirror(Dir8::of(&state.control_stick.dpad), c.x, c.y);
+        }
+        if let Some(c) = &then.c_stick {
+            o.c_stick = mirror(Dir8::of(&state.c_stick.dpad), c.x, c.y);
+        }
+        for &b in then.press.iter() {
+            b.set(&mut o.btn, true);
+        }
+        for &b in then.release.iter() {
+            b.set(&mut o.btn, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Button, Coords, Override};
+    use fixed::types::I1F7;
+
+    fn coords(x: f32, y: f32) -> Coords {
+        Coords {
+            x: I1F7::saturating_from_num(x),
+            y: I1F7::saturating_from_num(y),
+        }
+    }
+
+    fn rule(when: Condition, then: Override) -> Rule {
+        Rule { when, then }
+    }
+
+    // mod2, L and the stick held down
+    fn shielding() -> JoyState {
+        let mut state = JoyState::default();
+        state.m.set_mod2(true);
+        state.btn.set_l(true);
+        state.control_stick.dpad.set_down(true);
+        state
+    }
+
+    fn shield_drop() -> Condition {
+        Condition {
+            modifiers: Some(vec![Modifier::Mod2]),
+            any_buttons: vec![Button::L, Button::R],
+            control_stick: Some(vec![Dir8::S]),
+            ..Condition::default()
+        }
+    }
+
+    #[test]
+    fn conditions_need_every_field() {
+        let when = shield_drop();
+        assert!(when.holds(&shielding()));
+
+        let mut state = shielding();
+        state.m.set_mod1(true);
+        assert!(!when.holds(&state), "modifiers have to match exactly");
+
+        let mut state = shielding();
+        state.btn.set_l(false);
+        assert!(!when.holds(&state), "one of any_buttons has to be held");
+        state.btn.set_r(true);
+        assert!(when.holds(&state));
+
+        let mut state = shielding();
+        state.control_stick.dpad.set_right(true);
+        assert!(!when.holds(&state), "SE isn't S");
+
+        let when = Condition {
+            buttons: vec![Button::L, Button::B],
+            not_buttons: vec![Button::Z],
+            ..Condition::default()
+        };
+        let mut state = shielding();
+        assert!(!when.holds(&state));
+        state.btn.set_b(true);
+        assert!(when.holds(&state));
+        state.btn.set_z(true);
+        assert!(!when.holds(&state));
+    }
+
+    #[test]
+    fn later_rules_win() {
+        let rules = [
+            rule(
+                shield_drop(),
+                Override {
+                    control_stick: Some(coords(0.0, 0.43)),
+                    release: vec![Button::A, Button::B],
+                    ..Override::default()
+                },
+            ),
+            rule(
+                Condition {
+                    buttons: vec![Button::L],
+                    ..Condition::default()
+                },
+                Override {
+                    control_stick: Some(coords(0.25, 0.5)),
+                    press: vec![Button::A],
+                    ..Override::default()
+                },
+            ),
+        ];
+        let second = (
+            I1F7::saturating_from_num(0.25),
+            I1F7::saturating_from_num(0.5),
+        );
+        let state = shielding();
+        let mut o = Outputs::default();
+        o.btn.set_b(true);
+        apply(&rules, &state, &mut o);
+        // mirrored towards S, where y is positive
+        assert_eq!(o.control_stick, second);
+        assert!(o.btn.a());
+        assert!(!o.btn.b());
+
+        // without mod2 only the second one holds, so B isn't released
+        let mut state = shielding();
+        state.m.set_mod2(false);
+        let mut o = Outputs::default();
+        o.btn.set_b(true);
+        apply(&rules, &state, &mut o);
+        assert_eq!(o.control_stick, second);
+        assert!(o.btn.a());
+        assert!(o.btn.b());
+    }
+
+    #[test]
+    fn overrides_are_mirrored() {
+        let rules = [rule(
+            Condition::default(),
+            Override {
+                control_stick: Some(coords(0.5, 0.25)),
+                ..Override::default()
+            },
+        )];
+        let mut state = JoyState::default();
+        state.control_stick.dpad.set_up(true);
+        state.control_stick.dpad.set_left(true);
+        let mut o = Outputs::default();
+        apply(&rules, &state, &mut o);
+        assert_eq!(
+            o.control_stick,
+            (
+                I1F7::saturating_from_num(-0.5),
+                I1F7::saturating_from_num(-0.25)
+            )
+        );
+    }
+
+    #[test]
+    fn outputs_are_left_alone_without_a_match() {
+        let rules = [rule(
+            shield_drop(),
+            Override {
+                control_stick: Some(coords(0.0, 0.43)),
+                press: vec![Button::Z],
+                ..Override::default()
+            },
+        )];
+        let mut state = shielding();
+        state.m.set_mod2(false);
+        let mut o = Outputs {
+            control_stick: (I1F7::ZERO, I1F7::MAX),
+            ..Outputs::default()
+        };
+        o.btn.set_l(true);
+        let before = o;
+        apply(&rules, &state, &mut o);
+        assert_eq!(o, before);
+    }
+}
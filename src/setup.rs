@@ -0,0 +1,180 @@
+use crate::config::{Binds, DeviceMatch, Keys, Settings};
+use crate::discovery::{self, DeviceInfo, INPUT_DIR};
+use crate::keyboard::Source;
+use evdev_rs::{
+    enums::{EventCode, EV_KEY},
+    Device, DeviceWrapper, GrabMode, ReadFlag, ReadStatus,
+};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+const BY_ID_DIR: &str = "/dev/input/by-id";
+
+// every bind the wizard asks for, in the order it asks
+fn fields(b: &mut Binds) -> Vec<(&'static str, &mut Keys)> {
+    let (stick, c, dpad) = (&mut b.control_stick, &mut b.c_stick, &mut b.dpad);
+    vec![
+        ("control stick up", &mut stick.up),
+        ("control stick down", &mut stick.down),
+        ("control stick left", &mut stick.left),
+        ("control stick right", &mut stick.right),
+        ("control stick up-left", &mut stick.upleft),
+        ("control stick up-right", &mut stick.upright),
+        ("control stick down-left", &mut stick.downleft),
+        ("control stick down-right", &mut stick.downright),
+        ("mod1", &mut b.mod1),
+        ("mod2", &mut b.mod2),
+        ("A", &mut b.a),
+        ("B", &mut b.b),
+        ("X", &mut b.x),
+        ("Y", &mut b.y),
+        ("Z", &mut b.z),
+        ("L", &mut b.l),
+        ("R", &mut b.r),
+        ("Start", &mut b.start),
+        ("C-stick up", &mut c.up),
+        ("C-stick down", &mut c.down),
+        ("C-stick left", &mut c.left),
+        ("C-stick right", &mut c.right),
+        ("D-pad up", &mut dpad.up),
+        ("D-pad down", &mut dpad.down),
+        ("D-pad left", &mut dpad.left),
+        ("D-pad right", &mut dpad.right),
+    ]
+}
+
+/// Walks through every player: picks the keyboard that drives it, then asks for a key press for
+/// each bind. The result is written back to the config file.
+pub fn setup(mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut ask = |prompt: &str| -> io::Result<String> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        Ok(lines.next().transpose()?.unwrap_or_default())
+    };
+
+    for player in settings.players.iter_mut() {
+        let mut keyboards: Vec<(Device, DeviceInfo)> = discovery::scan(Path::new(INPUT_DIR))?
+            .iter()
+            .filter_map(|path| discovery::probe(path).ok())
+            .filter(|(device, info)| !info.is_ours() && discovery::is_keyboard(device))
+            .collect();
+        if keyboards.is_empty() {
+            return Err("no keyboards found; is this user allowed to read /dev/input?".into());
+        }
+
+        println!("\nKeyboards:");
+        for (i, (_, info)) in keyboards.iter().enumerate() {
+            println!("  {}) {} ({})", i + 1, info.name, info.path.display());
+        }
+        let i = loop {
+            let answer = ask(&format!("Keyboard for {:?} [1]: ", player.name))?;
+            match answer.trim() {
+                "" => break 0,
+                n => match n.parse::<usize>() {
+                    Ok(n) if (1..=keyboards.len()).contains(&n) => break n - 1,
+                    _ => println!("Pick a number between 1 and {}", keyboards.len()),
+                },
+            }
+        };
+        let (mut device, info) = keyboards.swap_remove(i);
+        player.devices = vec![device_match(&info)];
+
+        // keep the key presses out of the terminal
+        device.grab(GrabMode::Grab)?;
+        // the Enter that answered the prompt came from this keyboard too
+        wait_for_release(&device)?;
+        println!("\nPress the key for each bind, or Escape to keep the ones in brackets.");
+        let mut taken: Vec<(&'static str, EV_KEY)> = Vec::new();
+        for (name, bind) in fields(&mut player.binds) {
+            loop {
+                print!("  {} [{}]: ", name, bind);
+                io::stdout().flush()?;
+                let keys = match next_press(&mut device)? {
+                    EV_KEY::KEY_ESC => bind.clone(),
+                    key => Keys::from(key),
+                };
+                // chords are left to `check`, this only catches keys used twice
+                let conflict = keys
+                    .0
+                    .iter()
+                    .filter(|chord| chord.0.len() == 1)
+                    .find_map(|chord| taken.iter().find(|(_, k)| *k == chord.0[0]));
+                if let Some((other, key)) = conflict {
+                    println!("{:?} is already bound to {}", key, other);
+                    continue;
+                }
+                println!("{}", keys);
+                for chord in keys.0.iter().filter(|chord| chord.0.len() == 1) {
+                    taken.push((name, chord.0[0]));
+                }
+                *bind = keys;
+                break;
+            }
+        }
+        device.grab(GrabMode::Ungrab)?;
+    }
+
+    let answer = ask(&format!("\nWrite to {:?}? [Y/n] ", Settings::path()?))?;
+    if answer.trim().eq_ignore_ascii_case("n") {
+        println!("Nothing was written");
+        return Ok(());
+    }
+    let path = settings.save()?;
+    println!("Wrote {:?}", path);
+    Ok(())
+}
+
+fn next_press(device: &mut Device) -> io::Result<EV_KEY> {
+    loop {
+        let (status, ev) = device.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+        if let ReadStatus::Sync = status {
+            continue;
+        }
+        if let EventCode::EV_KEY(key) = ev.event_code {
+            if ev.value == 1 {
+                return Ok(key);
+            }
+        }
+    }
+}
+
+// throws away the presses that were queued up before the grab, and waits for the keys that are
+// still down to come up, so none of them is taken for the first bind
+fn wait_for_release(device: &Device) -> io::Result<()> {
+    loop {
+        while device.has_event_pending() {
+            device.next_event(ReadFlag::NORMAL)?;
+        }
+        if !device.any_held() {
+            return Ok(());
+        }
+        device.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+    }
+}
+
+// prefers a by-id link, since event numbers change between boots
+fn device_match(info: &DeviceInfo) -> DeviceMatch {
+    let node = fs::canonicalize(&info.path).ok();
+    let by_id = fs::read_dir(BY_ID_DIR).ok().and_then(|entries| {
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|link| node.is_some() && fs::canonicalize(link).ok() == node)
+    });
+    match by_id {
+        Some(link) => DeviceMatch {
+            path: Some(link.to_string_lossy().into_owned()),
+            ..DeviceMatch::default()
+        },
+        None => DeviceMatch {
+            name: Some(info.name.clone()),
+            vendor: Some(info.vendor),
+            product: Some(info.product),
+            ..DeviceMatch::default()
+        },
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::{self, Result};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+/// Signals that are read from a signalfd instead of being delivered, so that the main loop can
+/// wait for them along with everything else and shut down properly.
+pub struct Signals {
+    fd: RawFd,
+}
+
+impl Signals {
+    /// Blocks `signals` on the calling thread and hands them to the signalfd instead. Threads
+    /// inherit the mask, so this has to come before any are started.
+    pub fn new(signals: &[libc::c_int]) -> Result<Signals> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for &signal in signals {
+            unsafe { libc::sigaddset(&mut set, signal) };
+        }
+        let e = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+        if e != 0 {
+            return Err(io::Error::from_raw_os_error(e));
+        }
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Signals { fd })
+    }
+
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// The next signal that came in, without blocking.
+    pub fn take(&self) -> Option<libc::c_int> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        let n = unsafe { libc::read(self.fd, &mut info as *mut _ as *mut libc::c_void, size) };
+        if n == size as isize {
+            Some(info.ssi_signo as libc::c_int)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_are_read_instead_of_delivered() {
+        let signals = Signals::new(&[libc::SIGUSR1]).unwrap();
+        assert_eq!(signals.take(), None);
+        // would end the test run if it were delivered
+        unsafe { libc::raise(libc::SIGUSR1) };
+        assert_eq!(signals.take(), Some(libc::SIGUSR1));
+        assert_eq!(signals.take(), None);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Result};
+use std::path::Path;
+
+const EVENT_PAYLOADS: u8 = 0x35;
+const PRE_FRAME_UPDATE: u8 = 0x37;
+const GAME_END: u8 = 0x39;
+
+// the replay is a UBJSON object whose first key holds the raw event stream
+const RAW_PREFIX: &[u8] = b"{U\x03raw[$U#l";
+
+/// Physical button bits of a pre-frame update
+pub const DPAD_LEFT: u16 = 0x0001;
+pub const DPAD_RIGHT: u16 = 0x0002;
+pub const DPAD_DOWN: u16 = 0x0004;
+pub const DPAD_UP: u16 = 0x0008;
+pub const Z: u16 = 0x0010;
+pub const R: u16 = 0x0020;
+pub const L: u16 = 0x0040;
+pub const A: u16 = 0x0100;
+pub const B: u16 = 0x0200;
+pub const X: u16 = 0x0400;
+pub const Y: u16 = 0x0800;
+pub const START: u16 = 0x1000;
+
+/// The inputs the game saw for one player on one frame, after its own stick processing.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PreFrame {
+    pub frame: i32,
+    pub buttons: u16,
+    pub stick: (f32, f32),
+    pub c_stick: (f32, f32),
+    pub l: f32,
+}
+
+/// Reads the pre-frame inputs of every port from a Slippi replay. Frames that were rolled back
+/// only keep their final inputs.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<[Vec<PreFrame>; 4]> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if !data.starts_with(RAW_PREFIX) || data.len() < RAW_PREFIX.len() + 4 {
+        return Err(invalid("not a Slippi replay"));
+    }
+    let start = RAW_PREFIX.len() + 4;
+    let len = be_u32(&data, RAW_PREFIX.len()) as usize;
+    // replays that are still being written have a length of 0
+    let raw = match len {
+        0 => &data[start..],
+        len => data
+            .get(start..start + len)
+            .ok_or_else(|| invalid("truncated replay"))?,
+    };
+
+    if raw.len() < 2 || raw[0] != EVENT_PAYLOADS {
+        return Err(invalid("replay does not start with event payload sizes"));
+    }
+    let mut sizes = [None; 256];
+    let n = raw[1] as usize;
+    let payloads = raw
+        .get(2..1 + n)
+        .ok_or_else(|| invalid("truncated replay"))?;
+    for payload in payloads.chunks_exact(3) {
+        sizes[payload[0] as usize] = Some(u16::from_be_bytes([payload[1], payload[2]]) as usize);
+    }
+
+    let mut frames: [BTreeMap<i32, PreFrame>; 4] = Default::default();
+    let mut i = 1 + n;
+    while i < raw.len() {
+        let cmd = raw[i];
+        let size =
+            sizes[cmd as usize].ok_or_else(|| invalid(&format!("unknown event {:#04x}", cmd)))?;
+        let ev = match raw.get(i..i + 1 + size) {
+            Some(ev) => ev,
+            // the game was cut off mid-event
+            None => break,
+        };
+        i += 1 + size;
+        match cmd {
+            PRE_FRAME_UPDATE if ev.len() >= 0x37 => {
+                let (port, follower) = (ev[0x5] as usize, ev[0x6] != 0);
+                if port >= 4 || follower {
+                    continue;
+                }
+                let frame = be_u32(ev, 0x1) as i32;
+                frames[port].insert(
+                    frame,
+                    PreFrame {
+                        frame,
+                        buttons: u16::from_be_bytes([ev[0x31], ev[0x32]]),
+                        stick: (be_f32(ev, 0x19), be_f32(ev, 0x1D)),
+                        c_stick: (be_f32(ev, 0x21), be_f32(ev, 0x25)),
+                        l: be_f32(ev, 0x33),
+                    },
+                );
+            }
+            GAME_END => break,
+            _ => {}
+        }
+    }
+
+    Ok(frames.map(|frames| frames.into_values().collect()))
+}
+
+#[inline]
+fn be_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+#[inline]
+fn be_f32(b: &[u8], at: usize) -> f32 {
+    f32::from_bits(be_u32(b, at))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixture;
+
+    #[test]
+    fn reads_the_final_inputs_of_each_port() {
+        let ports = read(fixture("rollback.slp")).unwrap();
+        assert!(ports[1].is_empty());
+        assert!(ports[3].is_empty());
+
+        let frames: Vec<i32> = ports[0].iter().map(|pre| pre.frame).collect();
+        assert_eq!(frames, vec![-123, -122]);
+        // the rolled back B press is gone, and so are the follower's inputs
+        assert_eq!(ports[0][1].buttons, A);
+        assert_eq!(ports[0][1].stick, (0.0, -1.0));
+        assert_eq!(ports[0][0].l, 0.0);
+
+        assert_eq!(ports[2][0].buttons, L);
+        assert_eq!(ports[2][0].l, 1.0);
+        assert_eq!(ports[2][1].buttons, 0);
+        assert_eq!(ports[2][1].l, 0.375);
+        assert_eq!(ports[2][1].c_stick, (-0.5, 0.25));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let e = read(fixture("two_ports.dtm")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "not a Slippi replay");
+    }
+}
@@ -1,9 +1,12 @@
-use crate::config::{Binds, Settings};
+use crate::config::{Binds, Button, Chord, Keys, Settings};
 use crate::dpad::{DPadState, JoyStickState};
-use crate::vjoy::VJoy;
+use crate::keyset::{KeySet, KEY_CNT};
+use crate::output::OutputSink;
+use crate::rules;
 use evdev_rs::enums::{EV_ABS, EV_KEY};
 use fixed::types::I1F7;
 use modular_bitfield::{bitfield, specifiers::B6};
+use std::iter;
 // use std::collections::HashMap;
 
 #[derive(Copy, Clone, Default, Debug)]
@@ -20,16 +23,31 @@ pub struct JoyState {
     pub btn: JoyButtons,
     // modifiers
     pub m: Modifiers,
+    // what the pad outputs
+    pub out: Outputs,
+}
+
+/// Everything the pad outputs, after the angle table, rules and gate have been applied.
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub struct Outputs {
+    pub btn: JoyButtons,
+    pub dpad: DPadState,
+    pub control_stick: (I1F7, I1F7),
+    pub c_stick: (I1F7, I1F7),
+    pub l_trigger: I1F7,
 }
 
 #[bitfield]
 #[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
 pub struct JoyButtons {
+    pub a: bool,
+    pub b: bool,
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+    pub start: bool,
     pub l: bool,
     pub r: bool,
-    #[skip]
-    __: B6,
-    // dont store other buttons; they're not stateful enough to need to carry around
 }
 
 #[bitfield]
@@ -80,113 +98,226 @@ impl Default for StateUpdateKind {
     }
 }
 
+impl StateUpdateKind {
+    pub const ALL: [StateUpdateKind; 27] = [
+        Noop,
+        ControlStickLeft,
+        ControlStickDownLeft,
+        ControlStickUpLeft,
+        ControlStickRight,
+        ControlStickDownRight,
+        ControlStickUpRight,
+        ControlStickUp,
+        ControlStickDown,
+        CStickLeft,
+        CStickRight,
+        CStickUp,
+        CStickDown,
+        DPadLeft,
+        DPadRight,
+        DPadUp,
+        DPadDown,
+        BtnA,
+        BtnB,
+        BtnZ,
+        BtnX,
+        BtnY,
+        BtnStart,
+        BtnL,
+        BtnR,
+        Mod1,
+        Mod2,
+    ];
+
+    /// The two directions a control stick diagonal is made of. Any other action is just itself.
+    #[inline]
+    fn directions(self) -> (StateUpdateKind, Option<StateUpdateKind>) {
+        match self {
+            ControlStickUpLeft => (ControlStickUp, Some(ControlStickLeft)),
+            ControlStickUpRight => (ControlStickUp, Some(ControlStickRight)),
+            ControlStickDownLeft => (ControlStickDown, Some(ControlStickLeft)),
+            ControlStickDownRight => (ControlStickDown, Some(ControlStickRight)),
+            kind => (kind, None),
+        }
+    }
+
+    /// The inverse of the `Debug` formatting, as used in recordings.
+    pub fn from_name(name: &str) -> Option<StateUpdateKind> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| format!("{:?}", kind) == name)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct StateUpdate {
-    kind: StateUpdateKind,
-    value: bool,
+    pub kind: StateUpdateKind,
+    pub value: bool,
 }
 
-#[repr(transparent)]
+impl Binds {
+    /// Every action together with the keys bound to it.
+    pub fn actions(&self) -> [(StateUpdateKind, &Keys); 26] {
+        [
+            (ControlStickDown, &self.control_stick.down),
+            (ControlStickUp, &self.control_stick.up),
+            (ControlStickLeft, &self.control_stick.left),
+            (ControlStickRight, &self.control_stick.right),
+            (ControlStickDownLeft, &self.control_stick.downleft),
+            (ControlStickDownRight, &self.control_stick.downright),
+            (ControlStickUpLeft, &self.control_stick.upleft),
+            (ControlStickUpRight, &self.control_stick.upright),
+            (CStickDown, &self.c_stick.down),
+            (CStickUp, &self.c_stick.up),
+            (CStickLeft, &self.c_stick.left),
+            (CStickRight, &self.c_stick.right),
+            (DPadDown, &self.dpad.down),
+            (DPadUp, &self.dpad.up),
+            (DPadLeft, &self.dpad.left),
+            (DPadRight, &self.dpad.right),
+            (BtnA, &self.a),
+            (BtnB, &self.b),
+            (BtnX, &self.x),
+            (BtnY, &self.y),
+            (BtnZ, &self.z),
+            (BtnStart, &self.start),
+            (BtnL, &self.l),
+            (BtnR, &self.r),
+            (Mod1, &self.mod1),
+            (Mod2, &self.mod2),
+        ]
+    }
+}
+
+/// Which actions are triggered by which keys.
 #[derive(Clone)]
 pub struct BindsMap {
-    binds: [StateUpdateKind; 594],
+    // every chord with the action it triggers
+    chords: Vec<(Vec<EV_KEY>, StateUpdateKind)>,
+    // for every key code, the chords it is part of
+    by_key: Vec<Vec<usize>>,
+}
+
+/// Which chords are held, and how many of them hold each action. An action is only released once
+/// the last chord holding it is, so two keys can share a direction. Diagonals are counted as both
+/// of their directions, so letting go of one doesn't release a direction another chord holds.
+#[derive(Clone, Default, Debug)]
+pub struct BindsState {
+    held: Vec<bool>,
+    counts: [u8; StateUpdateKind::ALL.len()],
+}
+
+impl BindsState {
+    #[inline]
+    fn count<F: FnMut(StateUpdate)>(&mut self, kind: StateUpdateKind, value: bool, f: &mut F) {
+        let (first, second) = kind.directions();
+        for kind in iter::once(first).chain(second) {
+            let n = &mut self.counts[kind as usize];
+            let changed = if value {
+                *n += 1;
+                *n == 1
+            } else {
+                *n = n.saturating_sub(1);
+                *n == 0
+            };
+            if changed {
+                f(StateUpdate { kind, value });
+            }
+        }
+    }
 }
 
 impl BindsMap {
+    /// Builds the lookup table, with room for every key code the kernel has.
     pub fn create(cfg: &Binds) -> BindsMap {
-        let mut r: [StateUpdateKind; 594] = [Noop; 594];
-
-        r[cfg.control_stick.down as usize] = ControlStickDown;
-        r[cfg.control_stick.up as usize] = ControlStickUp;
-        r[cfg.control_stick.left as usize] = ControlStickLeft;
-        r[cfg.control_stick.right as usize] = ControlStickRight;
-        r[cfg.control_stick.downleft as usize] = ControlStickDownLeft;
-        r[cfg.control_stick.downright as usize] = ControlStickDownRight;
-        r[cfg.control_stick.upleft as usize] = ControlStickUpLeft;
-        r[cfg.control_stick.upright as usize] = ControlStickUpRight;
-        r[cfg.c_stick.down as usize] = CStickDown;
-        r[cfg.c_stick.up as usize] = CStickUp;
-        r[cfg.c_stick.left as usize] = CStickLeft;
-        r[cfg.c_stick.right as usize] = CStickRight;
-        r[cfg.dpad.down as usize] = DPadDown;
-        r[cfg.dpad.up as usize] = DPadUp;
-        r[cfg.dpad.left as usize] = DPadLeft;
-        r[cfg.dpad.right as usize] = DPadRight;
-        r[cfg.a as usize] = BtnA;
-        r[cfg.b as usize] = BtnB;
-        r[cfg.x as usize] = BtnX;
-        r[cfg.y as usize] = BtnY;
-        r[cfg.z as usize] = BtnZ;
-        r[cfg.start as usize] = BtnStart;
-        r[cfg.l as usize] = BtnL;
-        r[cfg.r as usize] = BtnR;
-        r[cfg.mod1 as usize] = Mod1;
-        r[cfg.mod2 as usize] = Mod2;
-
-        // let mut r = HashMap::<EV_KEY, StateUpdateKind>::new();
-        // r.insert(cfg.control_stick.down, ControlStickDown);
-        // r.insert(cfg.control_stick.up, ControlStickUp);
-        // r.insert(cfg.control_stick.left, ControlStickLeft);
-        // r.insert(cfg.control_stick.right, ControlStickRight);
-        // r.insert(cfg.control_stick.downleft, ControlStickDownLeft);
-        // r.insert(cfg.control_stick.downright, ControlStickDownRight);
-        // r.insert(cfg.control_stick.upleft, ControlStickUpLeft);
-        // r.insert(cfg.control_stick.upright, ControlStickUpRight);
-        // r.insert(cfg.c_stick.down, CStickDown);
-        // r.insert(cfg.c_stick.up, CStickUp);
-        // r.insert(cfg.c_stick.left, CStickLeft);
-        // r.insert(cfg.c_stick.right, CStickRight);
-        // r.insert(cfg.dpad.down, DPadDown);
-        // r.insert(cfg.dpad.up, DPadUp);
-        // r.insert(cfg.dpad.left, DPadLeft);
-        // r.insert(cfg.dpad.right, DPadRight);
-        // r.insert(cfg.a, BtnA);
-        // r.insert(cfg.b, BtnB);
-        // r.insert(cfg.x, BtnX);
-        // r.insert(cfg.y, BtnY);
-        // r.insert(cfg.z, BtnZ);
-        // r.insert(cfg.start, BtnStart);
-        // r.insert(cfg.l, BtnL);
-        // r.insert(cfg.r, BtnR);
-        // r.insert(cfg.mod1, Mod1);
-        // r.insert(cfg.mod2, Mod2);
-        //
-        BindsMap { binds: r }
+        let mut chords = Vec::new();
+        let mut by_key = vec![Vec::new(); KEY_CNT];
+        for &(kind, keys) in cfg.actions().iter() {
+            for Chord(chord) in keys.0.iter() {
+                if chord.is_empty() {
+                    continue;
+                }
+                for &key in chord.iter() {
+                    by_key[key as usize].push(chords.len());
+                }
+                chords.push((chord.clone(), kind));
+            }
+        }
+        BindsMap { chords, by_key }
     }
 
     #[inline]
-    pub fn lookup_key(&self, key: EV_KEY, value: bool) -> Option<StateUpdate> {
-        let kind = self.binds[key as usize];
-        Some(StateUpdate { kind, value })
+    pub fn is_bound(&self, key: EV_KEY) -> bool {
+        self.by_key
+            .get(key as usize)
+            .is_some_and(|chords| !chords.is_empty())
+    }
+
+    /// Updates the chords `key` is part of after it was pressed or released, where `keys` is
+    /// everything that is held now. `f` gets every action that starts or stops. Returns whether
+    /// `key` is bound at all.
+    #[inline]
+    pub fn update<F: FnMut(StateUpdate)>(
+        &self,
+        key: EV_KEY,
+        keys: &KeySet,
+        state: &mut BindsState,
+        mut f: F,
+    ) -> bool {
+        let chords = match self.by_key.get(key as usize) {
+            Some(chords) if !chords.is_empty() => chords,
+            _ => return false,
+        };
+        state.held.resize(self.chords.len(), false);
+        for &i in chords.iter() {
+            let (chord, kind) = &self.chords[i];
+            let held = chord.iter().all(|&k| keys.contains(k));
+            if held != state.held[i] {
+                state.held[i] = held;
+                state.count(*kind, held, &mut f);
+            }
+        }
+        true
+    }
+
+    /// Starts over from nothing held, then presses every chord that `keys` holds.
+    pub fn replay<F: FnMut(StateUpdate)>(&self, keys: &KeySet, state: &mut BindsState, mut f: F) {
+        *state = BindsState::default();
+        state.held.resize(self.chords.len(), false);
+        for (i, (chord, kind)) in self.chords.iter().enumerate() {
+            if chord.iter().all(|&k| keys.contains(k)) {
+                state.held[i] = true;
+                state.count(*kind, true, &mut f);
+            }
+        }
     }
 }
 
 impl StateUpdate {
+    /// Applies the update to `state`, including the outputs it leads to. Nothing is written out;
+    /// that is left to whoever ran the update, once all updates of an input report are in.
     #[inline]
-    pub fn run(self, state: &mut JoyState, vjoy: &VJoy, settings: &Settings) {
+    pub fn run(self, state: &mut JoyState, settings: &Settings) {
         match self.kind {
             Noop => {
                 return;
             }
 
             BtnA => {
-                // state.btn.set_a(self.value);
-                vjoy.key(EV_KEY::BTN_EAST, self.value);
+                state.btn.set_a(self.value);
             }
 
             BtnB => {
-                // state.btn.set_b(self.value);
-                vjoy.key(EV_KEY::BTN_SOUTH, self.value);
+                state.btn.set_b(self.value);
             }
 
             BtnX => {
-                // state.btn.set_x(self.value);
-                vjoy.key(EV_KEY::BTN_NORTH, self.value);
+                state.btn.set_x(self.value);
             }
 
             BtnY => {
-                // state.btn.set_y(self.value);
-                vjoy.key(EV_KEY::BTN_TL, self.value);
+                state.btn.set_y(self.value);
             }
 
             BtnL => {
@@ -200,244 +331,397 @@ impl StateUpdate {
                 } else {
                     I1F7::ZERO
                 };
-                vjoy.trigger(EV_ABS::ABS_Z, state.l_trigger);
             }
 
             BtnR => {
                 state.btn.set_r(self.value);
-                vjoy.key(EV_KEY::BTN_TR, self.value);
             }
 
             BtnZ => {
-                // state.btn.set_z(self.value);
-                vjoy.key(EV_KEY::BTN_Z, self.value);
+                state.btn.set_z(self.value);
             }
 
             BtnStart => {
-                // state.btn.set_start(self.value);
-                vjoy.key(EV_KEY::BTN_START, self.value);
+                state.btn.set_start(self.value);
             }
 
             DPadLeft => {
-                state.dpad.on_left(self.value);
-                vjoy.key(EV_KEY::BTN_DPAD_LEFT, self.value);
+                state.dpad.on_left(self.value, settings.socd.dpad.x);
             }
 
             DPadRight => {
-                state.dpad.on_right(self.value);
-                vjoy.key(EV_KEY::BTN_DPAD_RIGHT, self.value);
+                state.dpad.on_right(self.value, settings.socd.dpad.x);
             }
 
             DPadUp => {
-                state.dpad.on_up(self.value);
-                vjoy.key(EV_KEY::BTN_DPAD_UP, self.value);
+                state.dpad.on_up(self.value, settings.socd.dpad.y);
             }
 
             DPadDown => {
-                state.dpad.on_down(self.value);
-                vjoy.key(EV_KEY::BTN_DPAD_DOWN, self.value);
+                state.dpad.on_down(self.value, settings.socd.dpad.y);
             }
 
             CStickLeft => {
-                state.c_stick.dpad.on_left(self.value);
-                state.c_stick.update_x(None);
-                vjoy.joystick(EV_ABS::ABS_RX, state.c_stick.x);
+                state
+                    .c_stick
+                    .dpad
+                    .on_left(self.value, settings.socd.c_stick.x);
+                state.c_stick.update_x();
             }
 
             CStickRight => {
-                state.c_stick.dpad.on_right(self.value);
-                state.c_stick.update_x(None);
-                vjoy.joystick(EV_ABS::ABS_RX, state.c_stick.x);
+                state
+                    .c_stick
+                    .dpad
+                    .on_right(self.value, settings.socd.c_stick.x);
+                state.c_stick.update_x();
             }
 
             CStickUp => {
-                state.c_stick.dpad.on_up(self.value);
-                state.c_stick.update_y(None);
-                vjoy.joystick(EV_ABS::ABS_RY, state.c_stick.y);
+                state
+                    .c_stick
+                    .dpad
+                    .on_up(self.value, settings.socd.c_stick.y);
+                state.c_stick.update_y();
             }
 
             CStickDown => {
-                state.c_stick.dpad.on_down(self.value);
-                state.c_stick.update_y(None);
-                vjoy.joystick(EV_ABS::ABS_RY, state.c_stick.y);
+                state
+                    .c_stick
+                    .dpad
+                    .on_down(self.value, settings.socd.c_stick.y);
+                state.c_stick.update_y();
             }
 
             ////////////////////////////////////////////////////////////////////////////////
             // Control stick
             ControlStickLeft => {
-                state.control_stick.dpad.on_left(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
+                state
+                    .control_stick
+                    .dpad
+                    .on_left(self.value, settings.socd.control_stick.x);
             }
 
             ControlStickRight => {
-                state.control_stick.dpad.on_right(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
+                state
+                    .control_stick
+                    .dpad
+                    .on_right(self.value, settings.socd.control_stick.x);
             }
 
             ControlStickUp => {
-                state.control_stick.dpad.on_up(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
+                state
+                    .control_stick
+                    .dpad
+                    .on_up(self.value, settings.socd.control_stick.y);
             }
 
             ControlStickDown => {
-                state.control_stick.dpad.on_down(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else if state.m.mod2() && (state.btn.l() || state.btn.r()) {
-                    const SHIELD_DROP_Y_MUL: f32 = 0.43_f32;
-                    // shield drop special case
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), I1F7::from_num(SHIELD_DROP_Y_MUL)));
-                } else {
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
+                state
+                    .control_stick
+                    .dpad
+                    .on_down(self.value, settings.socd.control_stick.y);
             }
 
             ControlStickDownLeft => {
-                state.control_stick.dpad.on_down(self.value);
-                state.control_stick.dpad.on_left(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
+                let socd = settings.socd.control_stick;
+                state.control_stick.dpad.on_down(self.value, socd.y);
+                state.control_stick.dpad.on_left(self.value, socd.x);
             }
 
             ControlStickDownRight => {
-                state.control_stick.dpad.on_down(self.value);
-                state.control_stick.dpad.on_right(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
+                let socd = settings.socd.control_stick;
+                state.control_stick.dpad.on_down(self.value, socd.y);
+                state.control_stick.dpad.on_right(self.value, socd.x);
             }
 
             ControlStickUpLeft => {
-                state.control_stick.dpad.on_up(self.value);
-                state.control_stick.dpad.on_left(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
+                let socd = settings.socd.control_stick;
+                state.control_stick.dpad.on_up(self.value, socd.y);
+                state.control_stick.dpad.on_left(self.value, socd.x);
             }
 
             ControlStickUpRight => {
-                state.control_stick.dpad.on_up(self.value);
-                state.control_stick.dpad.on_right(self.value);
-                if state.m.mod1() {
-                    state
-                        .control_stick
-                        .step(settings.mod1_incr, settings.mod1_around_y);
-                } else {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                }
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
+                let socd = settings.socd.control_stick;
+                state.control_stick.dpad.on_up(self.value, socd.y);
+                state.control_stick.dpad.on_right(self.value, socd.x);
             }
 
             ////////////////////////////////////////////////////////////////////////////////
             Mod1 => {
                 state.m.set_mod1(self.value);
-                if !self.value {
-                    state
-                        .control_stick
-                        .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                    state
-                        .control_stick
-                        .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                    vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
-                    vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
-                }
             }
 
             Mod2 => {
                 state.m.set_mod2(self.value);
-                state
-                    .control_stick
-                    .update_x(mod2_mul(state.m.mod2(), settings.mod2_x_mul));
-                state
-                    .control_stick
-                    .update_y(mod2_mul(state.m.mod2(), settings.mod2_y_mul));
-                vjoy.joystick(EV_ABS::ABS_X, state.control_stick.x);
-                vjoy.joystick(EV_ABS::ABS_Y, state.control_stick.y);
             }
         }
-        vjoy.sync();
+
+        state
+            .control_stick
+            .update_angle(state.m, state.btn, &settings.angles, settings.gate);
+        state.out = Outputs::of(state, settings);
     }
 }
 
-#[inline(always)]
-fn mod2_mul(mod2: bool, mul: I1F7) -> Option<I1F7> {
-    if mod2 {
-        Some(mul)
-    } else {
-        None
+const BUTTON_KEYS: [(Button, EV_KEY); 7] = [
+    (Button::A, EV_KEY::BTN_EAST),
+    (Button::B, EV_KEY::BTN_SOUTH),
+    (Button::X, EV_KEY::BTN_NORTH),
+    (Button::Y, EV_KEY::BTN_TL),
+    (Button::Z, EV_KEY::BTN_Z),
+    (Button::R, EV_KEY::BTN_TR),
+    (Button::Start, EV_KEY::BTN_START),
+    // L is analog only
+];
+
+impl Outputs {
+    pub fn of(state: &JoyState, settings: &Settings) -> Outputs {
+        let stick = &state.control_stick;
+        let c_stick = &state.c_stick;
+        let mut o = Outputs {
+            btn: state.btn,
+            dpad: state.dpad,
+            control_stick: (stick.x, stick.y),
+            c_stick: settings.gate.clamp((c_stick.x, c_stick.y)),
+            l_trigger: state.l_trigger,
+        };
+        // the control stick went through the gate in the angle table, and rules are exact
+        rules::apply(&settings.rules, state, &mut o);
+        if o.btn.l() != state.btn.l() {
+            o.l_trigger = if o.btn.l() { I1F7::MAX } else { I1F7::ZERO };
+        }
+        o
+    }
+
+    /// Writes every output. Unchanged ones are dropped by `Dedup`.
+    pub fn write_to<O: OutputSink + ?Sized>(&self, out: &O) {
+        for &(button, key) in BUTTON_KEYS.iter() {
+            out.key(key, button.held(self.btn));
+        }
+        out.key(EV_KEY::BTN_DPAD_UP, self.dpad.up());
+        out.key(EV_KEY::BTN_DPAD_DOWN, self.dpad.down());
+        out.key(EV_KEY::BTN_DPAD_LEFT, self.dpad.left());
+        out.key(EV_KEY::BTN_DPAD_RIGHT, self.dpad.right());
+        out.joystick(EV_ABS::ABS_X, self.control_stick.0);
+        out.joystick(EV_ABS::ABS_Y, self.control_stick.1);
+        out.joystick(EV_ABS::ABS_RX, self.c_stick.0);
+        out.joystick(EV_ABS::ABS_RY, self.c_stick.1);
+        out.trigger(EV_ABS::ABS_Z, self.l_trigger);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Player, SocdMode};
+    use crate::output::{Dedup, OutputEvent, RecordingSink};
+
+    // runs every update as a report of its own, the way a pad would, and returns what was sent
+    fn run(
+        settings: &Settings,
+        updates: &[(StateUpdateKind, bool)],
+    ) -> (Outputs, Vec<OutputEvent>) {
+        let sink = RecordingSink::default();
+        let out = Dedup::new(Box::new(sink.clone()));
+        out.neutral();
+        sink.take();
+        let mut state = JoyState::default();
+        for &(kind, value) in updates.iter() {
+            StateUpdate { kind, value }.run(&mut state, settings);
+            state.out.write_to(&out);
+            out.sync();
+        }
+        (state.out, sink.take())
+    }
+
+    fn axis(events: &[OutputEvent], axis: EV_ABS) -> Vec<I1F7> {
+        events
+            .iter()
+            .filter_map(|ev| match *ev {
+                OutputEvent::Joystick(a, value) if a == axis => Some(value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn keys(events: &[OutputEvent]) -> Vec<(EV_KEY, bool)> {
+        events
+            .iter()
+            .filter_map(|ev| match *ev {
+                OutputEvent::Key(key, value) => Some((key, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn num(x: f32) -> I1F7 {
+        I1F7::saturating_from_num(x)
+    }
+
+    fn near(value: I1F7, x: f32) -> bool {
+        (value.to_num::<f32>() - x).abs() < 0.02
+    }
+
+    #[test]
+    fn last_input_brings_back_the_other_direction() {
+        let settings = Settings::default();
+        let (out, events) = run(
+            &settings,
+            &[
+                (ControlStickLeft, true),
+                (ControlStickRight, true),
+                (ControlStickRight, false),
+            ],
+        );
+        assert_eq!(
+            axis(&events, EV_ABS::ABS_X),
+            [num(-1.0), I1F7::MAX, num(-1.0)]
+        );
+        assert_eq!(out.control_stick, (num(-1.0), I1F7::ZERO));
+    }
+
+    #[test]
+    fn neutral_socd_cancels_out() {
+        let mut settings = Settings::default();
+        settings.socd.control_stick.x = SocdMode::Neutral;
+        let (_, events) = run(
+            &settings,
+            &[
+                (ControlStickLeft, true),
+                (ControlStickRight, true),
+                (ControlStickRight, false),
+            ],
+        );
+        assert_eq!(
+            axis(&events, EV_ABS::ABS_X),
+            [num(-1.0), I1F7::ZERO, num(-1.0)]
+        );
+    }
+
+    #[test]
+    fn first_input_socd_keeps_the_first_direction() {
+        let mut settings = Settings::default();
+        settings.socd.dpad.x = SocdMode::FirstInput;
+        let (_, events) = run(
+            &settings,
+            &[(DPadLeft, true), (DPadRight, true), (DPadLeft, false)],
+        );
+        assert_eq!(
+            keys(&events),
+            [
+                (EV_KEY::BTN_DPAD_LEFT, true),
+                (EV_KEY::BTN_DPAD_LEFT, false),
+                (EV_KEY::BTN_DPAD_RIGHT, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn diagonals_share_directions_with_other_chords() {
+        // S is down and Z is down-left
+        let map = BindsMap::create(&Player::default().binds);
+        let mut held = BindsState::default();
+        let mut keys = KeySet::default();
+        let mut updates = Vec::new();
+        for &(key, value) in [
+            (EV_KEY::KEY_S, true),
+            (EV_KEY::KEY_Z, true),
+            (EV_KEY::KEY_Z, false),
+        ]
+        .iter()
+        {
+            keys.set(key, value);
+            map.update(key, &keys, &mut held, |update| {
+                updates.push((update.kind, update.value))
+            });
+        }
+        assert_eq!(
+            updates,
+            [
+                (ControlStickDown, true),
+                (ControlStickLeft, true),
+                (ControlStickLeft, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn diagonal_is_one_report_on_the_gate() {
+        let settings = Settings::default();
+        let (out, events) = run(&settings, &[(ControlStickUpRight, true)]);
+        let (x, y) = out.control_stick;
+        assert!(near(x, 0.7) && near(y, -0.7), "{:?}", out.control_stick);
+        assert_eq!(
+            events,
+            [
+                OutputEvent::Joystick(EV_ABS::ABS_X, x),
+                OutputEvent::Joystick(EV_ABS::ABS_Y, y),
+                OutputEvent::Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn two_directions_make_the_same_diagonal() {
+        let settings = Settings::default();
+        let (diagonal, _) = run(&settings, &[(ControlStickUpRight, true)]);
+        let (both, _) = run(
+            &settings,
+            &[(ControlStickUp, true), (ControlStickRight, true)],
+        );
+        assert_eq!(both.control_stick, diagonal.control_stick);
+    }
+
+    #[test]
+    fn modifiers_pick_angles_from_the_table() {
+        let settings = Settings::default();
+        let (_, events) = run(
+            &settings,
+            &[
+                (Mod1, true),
+                (ControlStickRight, true),
+                (Mod1, false),
+                (Mod2, true),
+                (ControlStickRight, false),
+                (ControlStickUp, true),
+            ],
+        );
+        assert_eq!(
+            axis(&events, EV_ABS::ABS_X),
+            [num(0.6625), I1F7::MAX, num(0.3375), I1F7::ZERO]
+        );
+        assert_eq!(axis(&events, EV_ABS::ABS_Y), [num(-0.7375)]);
+    }
+
+    #[test]
+    fn table_angles_past_the_gate_are_kept() {
+        let settings = Settings::default();
+        let (out, _) = run(
+            &settings,
+            &[(Mod1, true), (BtnB, true), (ControlStickUpRight, true)],
+        );
+        assert_eq!(out.control_stick, (num(0.9125), num(-0.3875)));
+        let (out, _) = run(
+            &settings,
+            &[(Mod2, true), (BtnB, true), (ControlStickDownLeft, true)],
+        );
+        assert_eq!(out.control_stick, (num(-0.3875), num(0.9125)));
+    }
+
+    #[test]
+    fn mod1_makes_l_a_light_press() {
+        let settings = Settings::default();
+        let (out, events) = run(&settings, &[(Mod1, true), (BtnL, true)]);
+        assert_eq!(out.l_trigger, settings.mod1_trigger_mul);
+        assert_eq!(
+            events,
+            [
+                OutputEvent::Trigger(EV_ABS::ABS_Z, settings.mod1_trigger_mul),
+                OutputEvent::Sync,
+            ]
+        );
+        let (out, _) = run(&settings, &[(BtnL, true)]);
+        assert_eq!(out.l_trigger, I1F7::MAX);
     }
 }
@@ -0,0 +1,237 @@
+use crate::dpad::DPadState;
+use crate::epoll::Notify;
+use crate::state::JoyState;
+use crossbeam::channel::{self, Receiver, Sender};
+use cursive::views::{Canvas, LinearLayout, Panel};
+use cursive::{Cursive, Printer, Vec2};
+use fixed::types::I1F7;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// events sent while the dashboard is behind are dropped rather than stalling the input thread
+const CHANNEL_SIZE: usize = 1024;
+const LOG_LINES: usize = 256;
+
+const PLOT_W: usize = 21;
+const PLOT_H: usize = 11;
+const PAD_W: usize = 2 * PLOT_W + 3;
+const PAD_H: usize = PLOT_H + 8;
+
+pub enum TuiEvent {
+    /// The state of a pad after it handled an event
+    State { pad: usize, state: JoyState },
+    /// An input event, with how long it took from the kernel to our output
+    Log {
+        pad: usize,
+        text: String,
+        latency: Duration,
+    },
+    /// Closes the dashboard
+    Quit,
+}
+
+#[derive(Default)]
+struct Dashboard {
+    pads: Vec<JoyState>,
+    log: VecDeque<String>,
+}
+
+impl Dashboard {
+    fn apply(&mut self, ev: TuiEvent) {
+        match ev {
+            TuiEvent::State { pad, state } => {
+                if let Some(s) = self.pads.get_mut(pad) {
+                    *s = state;
+                }
+            }
+            TuiEvent::Log { pad, text, latency } => {
+                self.log.push_back(format!(
+                    "P{} {:<28} {:>8.3}ms",
+                    pad + 1,
+                    text,
+                    latency.as_secs_f64() * 1e3
+                ));
+                if self.log.len() > LOG_LINES {
+                    self.log.pop_front();
+                }
+            }
+            TuiEvent::Quit => {}
+        }
+    }
+}
+
+/// The dashboard's thread, and the channel to send it events on.
+pub struct Tui {
+    pub tx: Sender<TuiEvent>,
+    thread: JoinHandle<()>,
+}
+
+impl Tui {
+    /// Closes the dashboard if it is still open, and waits until it has given back the terminal.
+    pub fn close(self) {
+        let _ = self.tx.send(TuiEvent::Quit);
+        let _ = self.thread.join();
+    }
+}
+
+/// Starts the dashboard on its own thread, with a panel for each of the named pads. Quitting it
+/// sets off `quit`, so that the program can shut down.
+pub fn spawn(names: Vec<String>, quit: Arc<Notify>) -> Tui {
+    let (tx, rx) = channel::bounded(CHANNEL_SIZE);
+    let thread = thread::Builder::new()
+        .name(String::from("tui"))
+        .spawn(move || run(names, rx, quit))
+        .expect("could not start the dashboard thread");
+    Tui { tx, thread }
+}
+
+fn run(names: Vec<String>, rx: Receiver<TuiEvent>, quit: Arc<Notify>) {
+    let mut siv = cursive::default();
+    siv.add_global_callback('q', Cursive::quit);
+    siv.add_global_callback('~', Cursive::toggle_debug_console);
+
+    let dash = Arc::new(Mutex::new(Dashboard {
+        pads: vec![JoyState::default(); names.len()],
+        log: VecDeque::new(),
+    }));
+    // the views only read the dashboard when they are drawn, so updating it stays cheap
+    let feed = dash.clone();
+    let cb_sink = siv.cb_sink().clone();
+    thread::spawn(move || {
+        for ev in rx {
+            match ev {
+                TuiEvent::Quit => {
+                    let _ = cb_sink.send(Box::new(|siv: &mut Cursive| siv.quit()));
+                }
+                ev => feed.lock().unwrap().apply(ev),
+            }
+        }
+    });
+
+    let mut pads = LinearLayout::horizontal();
+    for (i, name) in names.iter().enumerate() {
+        let dash = dash.clone();
+        let canvas = Canvas::new(())
+            .with_draw(move |_, printer| draw_pad(printer, &dash.lock().unwrap().pads[i]))
+            .with_required_size(|_, _| Vec2::new(PAD_W, PAD_H));
+        pads.add_child(Panel::new(canvas).title(name.as_str()));
+    }
+    let log = Canvas::new(()).with_draw(move |_, printer| {
+        let dash = dash.lock().unwrap();
+        let skip = dash.log.len().saturating_sub(printer.size.y);
+        for (y, line) in dash.log.iter().skip(skip).enumerate() {
+            printer.print((0, y), line);
+        }
+    });
+    siv.add_fullscreen_layer(
+        LinearLayout::vertical()
+            .child(pads)
+            .child(Panel::new(log).title("Events (q to quit, ~ for the log)")),
+    );
+
+    siv.set_autorefresh(true);
+    siv.run();
+    quit.notify();
+}
+
+fn draw_pad(printer: &Printer, state: &JoyState) {
+    let out = &state.out;
+    plot(printer, (0, 0), "Control stick", out.control_stick);
+    plot(printer, (PLOT_W + 3, 0), "C-stick", out.c_stick);
+
+    let mut y = PLOT_H + 3;
+    let depth = out.l_trigger.to_num::<f32>().max(0.0);
+    let filled = (depth * 20.0).round() as usize;
+    printer.print(
+        (0, y),
+        &format!(
+            "L [{}{}] {:.2}",
+            "#".repeat(filled),
+            "-".repeat(20 - filled),
+            depth
+        ),
+    );
+    y += 1;
+
+    let btn = out.btn;
+    let held: Vec<&str> = [
+        (btn.a(), "A"),
+        (btn.b(), "B"),
+        (btn.x(), "X"),
+        (btn.y(), "Y"),
+        (btn.z(), "Z"),
+        (btn.l(), "L"),
+        (btn.r(), "R"),
+        (btn.start(), "Start"),
+        (out.dpad.up(), "D-up"),
+        (out.dpad.down(), "D-down"),
+        (out.dpad.left(), "D-left"),
+        (out.dpad.right(), "D-right"),
+    ]
+    .iter()
+    .filter(|(held, _)| *held)
+    .map(|(_, name)| *name)
+    .collect();
+    printer.print((0, y), &format!("Buttons: {}", held.join(" ")));
+    y += 1;
+
+    let m = state.m;
+    let mods: Vec<&str> = [(m.mod1(), "mod1"), (m.mod2(), "mod2")]
+        .iter()
+        .filter(|(held, _)| *held)
+        .map(|(_, name)| *name)
+        .collect();
+    printer.print((0, y), &format!("Modifiers: {}", mods.join(" ")));
+    y += 1;
+
+    for (name, dpad) in [
+        ("stick", state.control_stick.dpad),
+        ("C-stick", state.c_stick.dpad),
+        ("D-pad", state.dpad),
+    ]
+    .iter()
+    {
+        printer.print((0, y), &format!("SOCD {:<8}{}", name, socd(*dpad)));
+        y += 1;
+    }
+}
+
+// draws a stick's position within its square range, with up at the top
+fn plot(printer: &Printer, (x0, y0): (usize, usize), title: &str, (x, y): (I1F7, I1F7)) {
+    printer.print((x0, y0), title);
+    for row in 0..PLOT_H {
+        let line: String = (0..PLOT_W)
+            .map(|col| {
+                if col == PLOT_W / 2 && row == PLOT_H / 2 {
+                    '+'
+                } else {
+                    '·'
+                }
+            })
+            .collect();
+        printer.print((x0, y0 + 1 + row), &line);
+    }
+    let (x, y) = (x.to_num::<f32>(), y.to_num::<f32>());
+    let col = ((x + 1.0) / 2.0 * (PLOT_W - 1) as f32).round() as usize;
+    let row = ((y + 1.0) / 2.0 * (PLOT_H - 1) as f32).round() as usize;
+    printer.print((x0 + col, y0 + 1 + row), "●");
+    printer.print((x0, y0 + 1 + PLOT_H), &format!("{:+.4} {:+.4}", x, -y));
+}
+
+// the directions that are held, and what they resolve to
+fn socd(d: DPadState) -> String {
+    let arrows = |up: bool, down: bool, left: bool, right: bool| {
+        [(left, '←'), (up, '↑'), (down, '↓'), (right, '→')]
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, c)| *c)
+            .collect::<String>()
+    };
+    format!(
+        "held {:<4} out {}",
+        arrows(d.up_held(), d.down_held(), d.left_held(), d.right_held()),
+        arrows(d.up(), d.down(), d.left(), d.right())
+    )
+}
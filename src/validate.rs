@@ -0,0 +1,295 @@
+use crate::config::{Binds, Chord, OutputKind, Player, Settings};
+use crate::discovery::{self, INPUT_DIR};
+use crate::keyboard::Source;
+use crate::state::StateUpdateKind;
+use evdev_rs::{
+    enums::{EventCode, EV_KEY},
+    Device,
+};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Works, but probably not the way it was meant to
+    Warning,
+    /// Some action can never be triggered
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    fn error(message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}", level, self.message)
+    }
+}
+
+/// Checks the settings on their own, without looking at any devices.
+pub fn check(settings: &Settings) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    for (i, a) in settings.players.iter().enumerate() {
+        for b in &settings.players[i + 1..] {
+            if a.name == b.name {
+                diags.push(Diagnostic::warning(format!(
+                    "two players are named {:?}; Dolphin may mix them up",
+                    a.name
+                )));
+            }
+            if a.port == b.port {
+                diags.push(Diagnostic::warning(format!(
+                    "{:?} and {:?} are both on port {}",
+                    a.name, b.name, a.port
+                )));
+            }
+            let pipe = OutputKind::DolphinPipe;
+            if a.output == pipe && b.output == pipe && a.pipe_path() == b.pipe_path() {
+                diags.push(Diagnostic::error(format!(
+                    "{:?} and {:?} both write to the Dolphin pipe {:?}",
+                    a.name,
+                    b.name,
+                    a.pipe_path()
+                )));
+            }
+        }
+        check_binds(
+            &format!("{:?}", a.name),
+            &a.binds,
+            &a.profile_cycle,
+            &mut diags,
+        );
+        check_profiles(a, &mut diags);
+    }
+    diags
+}
+
+fn check_profiles(player: &Player, diags: &mut Vec<Diagnostic>) {
+    if player.profiles.is_empty() {
+        return;
+    }
+    if player.profile_cycle.0.is_empty() {
+        diags.push(Diagnostic::warning(format!(
+            "{:?}: profile_cycle is empty, so its profiles can't be switched to",
+            player.name
+        )));
+    }
+    for (i, profile) in player.profiles.iter().enumerate() {
+        if player.profiles[..i].iter().any(|p| p.name == profile.name) {
+            diags.push(Diagnostic::warning(format!(
+                "{:?}: two profiles are named {:?}",
+                player.name, profile.name
+            )));
+        }
+        if let Some(binds) = &profile.binds {
+            let name = format!("{:?} in profile {:?}", player.name, profile.name);
+            check_binds(&name, binds, &player.profile_cycle, diags);
+        }
+    }
+}
+
+// whether every key of `b` is also in `a`
+#[inline]
+fn covers(a: &[EV_KEY], b: &[EV_KEY]) -> bool {
+    b.iter().all(|k| a.contains(k))
+}
+
+// `name` says whose binds these are in the messages
+fn check_binds(name: &str, binds: &Binds, cycle: &Chord, diags: &mut Vec<Diagnostic>) {
+    let actions = binds.actions();
+    let mut chords: Vec<(StateUpdateKind, &Chord)> = Vec::new();
+    for &(kind, keys) in actions.iter() {
+        if keys.0.is_empty() {
+            diags.push(Diagnostic::warning(format!(
+                "{}: {:?} isn't bound to any key",
+                name, kind
+            )));
+        }
+        for chord in keys.0.iter() {
+            chords.push((kind, chord));
+        }
+    }
+
+    for (i, &(kind, chord)) in chords.iter().enumerate() {
+        for (j, &(other, sub)) in chords.iter().enumerate() {
+            if other == kind || !covers(&chord.0, &sub.0) {
+                continue;
+            }
+            if covers(&sub.0, &chord.0) {
+                if j < i {
+                    diags.push(Diagnostic::warning(format!(
+                        "{}: {} triggers both {:?} and {:?}",
+                        name, chord, other, kind
+                    )));
+                }
+            } else {
+                diags.push(Diagnostic::warning(format!(
+                    "{}: pressing {} for {:?} also triggers {:?} through {}",
+                    name, chord, kind, other, sub
+                )));
+            }
+        }
+    }
+
+    // the cycle chord is checked before the binds too
+    if !cycle.0.is_empty() {
+        for &(kind, chord) in chords
+            .iter()
+            .filter(|(_, chord)| covers(&chord.0, &cycle.0))
+        {
+            diags.push(Diagnostic::error(format!(
+                "{}: {} for {:?} includes all of profile_cycle, so it can never be triggered",
+                name, chord, kind
+            )));
+        }
+    }
+
+    let toggle = &binds.typing_toggle;
+    if toggle.0.is_empty() {
+        diags.push(Diagnostic::warning(format!(
+            "{}: typing_toggle is empty, so typing mode can't be entered",
+            name
+        )));
+        return;
+    }
+    // the toggle is checked before the binds, so holding it never gets as far as the chord
+    for &(kind, chord) in chords
+        .iter()
+        .filter(|(_, chord)| covers(&chord.0, &toggle.0))
+    {
+        diags.push(Diagnostic::error(format!(
+            "{}: {} for {:?} includes all of typing_toggle, so it can never be triggered",
+            name, chord, kind
+        )));
+    }
+}
+
+/// Checks that the keyboards of a player have every key it has bound.
+pub fn check_keyboards(player: &Player, devices: &[&dyn Source]) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    if devices.is_empty() {
+        return diags;
+    }
+    let profiles = player.profiles.iter().filter_map(|profile| {
+        profile
+            .binds
+            .as_ref()
+            .map(|binds| (Some(&profile.name), binds))
+    });
+    for (profile, binds) in std::iter::once((None, &player.binds)).chain(profiles) {
+        let name = match profile {
+            Some(profile) => format!("{:?} in profile {:?}", player.name, profile),
+            None => format!("{:?}", player.name),
+        };
+        for &(kind, keys) in binds.actions().iter() {
+            for &key in keys.0.iter().flat_map(|chord| chord.0.iter()) {
+                let advertised = devices
+                    .iter()
+                    .any(|device| device.has_event_code(&EventCode::EV_KEY(key)));
+                if !advertised {
+                    diags.push(Diagnostic::warning(format!(
+                        "{}: {:?} is bound to {:?}, which none of its keyboards have",
+                        name, kind, key
+                    )));
+                }
+            }
+        }
+    }
+    diags
+}
+
+/// Logs every diagnostic, and fails if any of them is an error.
+pub fn ensure(diags: &[Diagnostic]) -> Result<(), Box<dyn Error>> {
+    let mut errors = 0;
+    for diag in diags {
+        match diag.severity {
+            Severity::Warning => log::warn!("{}", diag.message),
+            Severity::Error => {
+                log::error!("{}", diag.message);
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        return Err(format!(
+            "the config has {} error{}, see above or run `melee-vpad check`",
+            errors,
+            if errors == 1 { "" } else { "s" }
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks the settings together with the keyboards that are plugged in right now, and prints what
+/// it finds.
+pub fn report(settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let mut diags = check(settings);
+
+    // hand out devices the same way `run` does
+    let mut devices: Vec<Vec<Device>> = settings.players.iter().map(|_| Vec::new()).collect();
+    for path in discovery::scan(Path::new(INPUT_DIR))? {
+        let (device, info) = match discovery::probe(&path) {
+            Ok(probed) => probed,
+            Err(_) => continue,
+        };
+        if info.is_ours() {
+            continue;
+        }
+        let wanted = settings
+            .players
+            .iter()
+            .position(|player| player.devices.iter().any(|m| m.matches(&info)));
+        if let Some(i) = wanted {
+            devices[i].push(device);
+        }
+    }
+    for (player, devices) in settings.players.iter().zip(devices.iter()) {
+        if devices.is_empty() {
+            diags.push(Diagnostic::warning(format!(
+                "{:?}: none of its input devices are plugged in",
+                player.name
+            )));
+        }
+        let devices: Vec<&dyn Source> =
+            devices.iter().map(|device| device as &dyn Source).collect();
+        diags.extend(check_keyboards(player, &devices));
+    }
+
+    for diag in diags.iter() {
+        println!("{}", diag);
+    }
+    let errors = diags
+        .iter()
+        .filter(|diag| diag.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(format!("{} errors, {} warnings", errors, diags.len() - errors).into());
+    }
+    println!("No errors, {} warnings", diags.len());
+    Ok(())
+}
@@ -0,0 +1,373 @@
+use crate::config::Settings;
+use crate::dtm::{self, FRAME_US};
+use crate::gc::{self, GcPad};
+use crate::record::{self, LogEntry, LogEvent};
+use crate::slp::{self, PreFrame};
+use crate::state::StateUpdate;
+use std::error::Error;
+use std::path::Path;
+
+// Melee reads a stick axis as (raw - 128) / 80 and zeroes anything inside the deadzone
+const STICK_UNIT: f32 = 1.0 / 80.0;
+const STICK_DEADZONE: f32 = 0.2875;
+const EPSILON: f32 = 1e-4;
+// how many frames a value may show up late before it counts as lost
+const MAX_LATE: usize = 3;
+// presses this short that never reach the game are reported as dropped taps
+const TAP_FRAMES: usize = 2;
+
+const BUTTONS: [(u16, u16, &str); 12] = [
+    (gc::A, slp::A, "A"),
+    (gc::B, slp::B, "B"),
+    (gc::X, slp::X, "X"),
+    (gc::Y, slp::Y, "Y"),
+    (gc::Z, slp::Z, "Z"),
+    (gc::L, slp::L, "L"),
+    (gc::R, slp::R, "R"),
+    (gc::START, slp::START, "Start"),
+    (gc::DPAD_UP, slp::DPAD_UP, "D-pad up"),
+    (gc::DPAD_DOWN, slp::DPAD_DOWN, "D-pad down"),
+    (gc::DPAD_LEFT, slp::DPAD_LEFT, "D-pad left"),
+    (gc::DPAD_RIGHT, slp::DPAD_RIGHT, "D-pad right"),
+];
+
+/// The inputs of one frame, in the form Slippi records them.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Seen {
+    buttons: u16,
+    stick: (f32, f32),
+    c_stick: (f32, f32),
+    l: bool,
+}
+
+impl Seen {
+    fn of_pad(pad: &GcPad) -> Seen {
+        Seen {
+            buttons: BUTTONS
+                .iter()
+                .filter(|(button, _, _)| pad.held(*button))
+                .fold(0, |acc, (_, bit, _)| acc | bit),
+            stick: stick(pad.stick_x, pad.stick_y),
+            c_stick: stick(pad.c_stick_x, pad.c_stick_y),
+            l: pad.trigger_l > 0,
+        }
+    }
+
+    fn of_frame(frame: &PreFrame) -> Seen {
+        let mask = BUTTONS.iter().fold(0, |acc, (_, bit, _)| acc | bit);
+        Seen {
+            buttons: frame.buttons & mask,
+            stick: frame.stick,
+            c_stick: frame.c_stick,
+            l: frame.l > 0.0,
+        }
+    }
+}
+
+fn stick(x: u8, y: u8) -> (f32, f32) {
+    let axis = |v: u8| (v as f32 - 128.0) * STICK_UNIT;
+    let (mut x, mut y) = (axis(x), axis(y));
+    let mag = x.hypot(y);
+    if mag > 1.0 {
+        x /= mag;
+        y /= mag;
+    }
+    let deadzone = |v: f32| if v.abs() < STICK_DEADZONE { 0.0 } else { v };
+    (deadzone(x), deadzone(y))
+}
+
+fn within(a: (f32, f32), b: (f32, f32), d: f32) -> bool {
+    (a.0 - b.0).abs() <= d + EPSILON && (a.1 - b.1).abs() <= d + EPSILON
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Field {
+    Button(usize),
+    Stick,
+    CStick,
+    LTrigger,
+}
+
+const FIELDS: [Field; 15] = [
+    Field::Button(0),
+    Field::Button(1),
+    Field::Button(2),
+    Field::Button(3),
+    Field::Button(4),
+    Field::Button(5),
+    Field::Button(6),
+    Field::Button(7),
+    Field::Button(8),
+    Field::Button(9),
+    Field::Button(10),
+    Field::Button(11),
+    Field::Stick,
+    Field::CStick,
+    Field::LTrigger,
+];
+
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::Button(i) => BUTTONS[i].2,
+            Field::Stick => "control stick",
+            Field::CStick => "C-stick",
+            Field::LTrigger => "L trigger",
+        }
+    }
+
+    fn pressed(self, s: &Seen) -> bool {
+        match self {
+            Field::Button(i) => s.buttons & BUTTONS[i].1 != 0,
+            Field::LTrigger => s.l,
+            Field::Stick | Field::CStick => false,
+        }
+    }
+
+    fn same(self, a: &Seen, b: &Seen) -> bool {
+        match self {
+            Field::Stick => within(a.stick, b.stick, 0.0),
+            Field::CStick => within(a.c_stick, b.c_stick, 0.0),
+            _ => self.pressed(a) == self.pressed(b),
+        }
+    }
+
+    fn show(self, s: &Seen) -> String {
+        match self {
+            Field::Stick => format!("({:.4}, {:.4})", s.stick.0, s.stick.1),
+            Field::CStick => format!("({:.4}, {:.4})", s.c_stick.0, s.c_stick.1),
+            _ if self.pressed(s) => String::from("pressed"),
+            _ => String::from("released"),
+        }
+    }
+
+    // works out why the game saw something else on frame i than we sent on frame k
+    fn classify(self, ours: &[Seen], k: usize, game: &[Seen], i: usize) -> String {
+        let late = (1..=MAX_LATE).find(|d| {
+            game.get(i + d)
+                .is_some_and(|seen| self.same(&ours[k], seen))
+        });
+        if let Some(d) = late {
+            return format!("late by {} frame{}", d, if d == 1 { "" } else { "s" });
+        }
+        match self {
+            Field::Stick if within(ours[k].stick, game[i].stick, STICK_UNIT) => {
+                String::from("quantization")
+            }
+            Field::CStick if within(ours[k].c_stick, game[i].c_stick, STICK_UNIT) => {
+                String::from("quantization")
+            }
+            Field::Stick | Field::CStick => String::from("different value"),
+            _ if self.pressed(&ours[k]) => {
+                let held = |s: &Seen| self.pressed(s);
+                let start = ours[..k]
+                    .iter()
+                    .rposition(|s| !held(s))
+                    .map_or(0, |j| j + 1);
+                let len = ours[start..].iter().take_while(|s| held(s)).count();
+                if len <= TAP_FRAMES {
+                    String::from("dropped tap")
+                } else {
+                    String::from("dropped press")
+                }
+            }
+            _ => String::from("press that was never sent"),
+        }
+    }
+}
+
+// the state update each frame of a pad was last changed by, along with when it happened
+fn last_updates(
+    entries: &[LogEntry],
+    pad: usize,
+    frames: usize,
+) -> Vec<Option<(u64, StateUpdate)>> {
+    let mut updates = entries
+        .iter()
+        .filter(|entry| entry.pad == pad)
+        .filter_map(|entry| match entry.event {
+            LogEvent::Update(update) => Some((entry.t, update)),
+            _ => None,
+        })
+        .peekable();
+    let mut last = None;
+    (0..frames as u64)
+        .map(|k| {
+            while let Some(update) = updates.next_if(|(t, _)| *t <= k * FRAME_US) {
+                last = Some(update);
+            }
+            last
+        })
+        .collect()
+}
+
+#[inline]
+fn all_same(a: &Seen, b: &Seen) -> bool {
+    FIELDS.iter().all(|field| field.same(a, b))
+}
+
+// pairs every game frame from `i0` on with one of our frames, starting at `k0`. The game doesn't
+// run at exactly FRAME_US, so wherever our frame before or after lines up with this game frame and
+// the next one while ours doesn't, the pairing moves over by that one frame. Returns the pairs,
+// each with which way it moved there, if at all.
+fn line_up(ours: &[Seen], theirs: &[Seen], k0: usize, i0: usize) -> Vec<(usize, usize, isize)> {
+    let lines_up = |k: usize, i: usize| {
+        (0..2).all(|n| match (ours.get(k + n), theirs.get(i + n)) {
+            (Some(a), Some(b)) => all_same(a, b),
+            _ => false,
+        })
+    };
+    let mut pairs = Vec::new();
+    let mut k = k0;
+    for (i, seen) in theirs.iter().enumerate().skip(i0) {
+        if k >= ours.len() {
+            break;
+        }
+        let mut moved = 0;
+        if !all_same(&ours[k], seen) {
+            if k > 0 && lines_up(k - 1, i) {
+                moved = -1;
+            } else if lines_up(k + 1, i) {
+                moved = 1;
+            }
+        }
+        k = (k as isize + moved) as usize;
+        pairs.push((i, k, moved));
+        k += 1;
+    }
+    pairs
+}
+
+/// Compares a recording against a Slippi replay of the same game and prints every frame where the
+/// game saw something other than what we sent. Recording and replay are lined up by the first frame
+/// on which anything is pressed, and then follow each other as the two clocks drift apart.
+pub fn verify(settings: &Settings, log: &Path, replay: &Path) -> Result<(), Box<dyn Error>> {
+    let entries = record::read_log(log)?;
+    let samples = dtm::sample(settings, &entries);
+    let game = slp::read(replay)?;
+
+    let pads = entries.iter().map(|entry| entry.pad + 1).max().unwrap_or(0);
+    let mut frames = 0;
+    let mut mismatches = 0;
+    let mut drifts = 0;
+    for pad in 0..pads {
+        let port = match dtm::port(settings, pad) {
+            Some(port) => port,
+            None => continue,
+        };
+        let ours: Vec<Seen> = samples
+            .iter()
+            .map(|frame| Seen::of_pad(&frame[port]))
+            .collect();
+        let theirs: Vec<Seen> = game[port].iter().map(Seen::of_frame).collect();
+        let neutral = Seen::default();
+        let (k0, i0) = match (
+            ours.iter().position(|s| *s != neutral),
+            theirs.iter().position(|s| *s != neutral),
+        ) {
+            (Some(k0), Some(i0)) => (k0, i0),
+            _ => {
+                log::warn!("Nothing to line up for port {}", port + 1);
+                continue;
+            }
+        };
+        log::info!(
+            "Port {}: recording frame {} is game frame {}",
+            port + 1,
+            k0,
+            game[port][i0].frame
+        );
+
+        let updates = last_updates(&entries, pad, ours.len());
+        for (i, k, moved) in line_up(&ours, &theirs, k0, i0) {
+            if moved != 0 {
+                drifts += 1;
+                println!(
+                    "port {} frame {}: the recording drifted a frame {}",
+                    port + 1,
+                    game[port][i].frame,
+                    if moved < 0 { "ahead" } else { "behind" }
+                );
+            }
+            frames += 1;
+            for field in FIELDS.iter() {
+                if field.same(&ours[k], &theirs[i]) {
+                    continue;
+                }
+                mismatches += 1;
+                let cause = match updates[k] {
+                    Some((t, update)) => format!(
+                        "{:?} {} at {:.3}s",
+                        update.kind,
+                        if update.value { "on" } else { "off" },
+                        t as f64 / 1e6
+                    ),
+                    None => String::from("nothing"),
+                };
+                println!(
+                    "port {} frame {}: {} was {} in game but {} was sent ({}), last update {}",
+                    port + 1,
+                    game[port][i].frame,
+                    field.name(),
+                    field.show(&theirs[i]),
+                    field.show(&ours[k]),
+                    field.classify(&ours, k, &theirs, i),
+                    cause
+                );
+            }
+        }
+    }
+
+    if mismatches > 0 {
+        return Err(format!("{} mismatches in {} frames", mismatches, frames).into());
+    }
+    log::info!(
+        "All {} frames match, after lining them up again {} times",
+        frames,
+        drifts
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(buttons: u16) -> Seen {
+        Seen {
+            buttons,
+            ..Seen::default()
+        }
+    }
+
+    #[test]
+    fn frames_line_up_again_after_a_drift() {
+        let ours: Vec<Seen> = [slp::A, slp::B, slp::X, slp::Y, slp::Z]
+            .iter()
+            .map(|&b| pressed(b))
+            .collect();
+        // the game showed B for one frame longer
+        let theirs: Vec<Seen> = [slp::A, slp::B, slp::B, slp::X, slp::Y, slp::Z]
+            .iter()
+            .map(|&b| pressed(b))
+            .collect();
+        let pairs = line_up(&ours, &theirs, 0, 0);
+        assert_eq!(pairs.len(), theirs.len());
+        assert_eq!(pairs[2], (2, 1, -1));
+        assert!(pairs
+            .iter()
+            .all(|&(i, k, _)| all_same(&ours[k], &theirs[i])));
+    }
+
+    #[test]
+    fn single_mismatches_dont_move_the_frames() {
+        let ours: Vec<Seen> = [slp::A, slp::B, slp::X, slp::Y]
+            .iter()
+            .map(|&b| pressed(b))
+            .collect();
+        let mut theirs = ours.clone();
+        theirs[2] = pressed(slp::Z);
+        let pairs = line_up(&ours, &theirs, 0, 0);
+        assert_eq!(pairs, [(0, 0, 0), (1, 1, 0), (2, 2, 0), (3, 3, 0)]);
+    }
+}
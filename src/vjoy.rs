@@ -1,21 +1,27 @@
-use crate::config::{Settings, JOY_DOWN_RANGE, JOY_UP_RANGE};
+use crate::config::{Player, JOY_DOWN_RANGE, JOY_UP_RANGE};
+use crate::discovery;
+use crate::keyboard;
+use crate::output::OutputSink;
 use evdev_rs::{
     enums::{EventCode, EventType, EV_ABS, EV_KEY, EV_SYN},
     AbsInfo, DeviceWrapper, EnableCodeData, InputEvent, TimeVal, UInputDevice, UninitDevice,
 };
 use fixed::types::I1F7;
 use log;
+use std::cell::Cell;
 use std::io::Result;
 
 pub struct VJoy {
     pub device: UInputDevice,
-    pub now: TimeVal,
+    // the time of the input the outputs come from
+    now: Cell<TimeVal>,
 }
 
 impl VJoy {
-    pub fn new(_cfg: &Settings) -> Result<VJoy> {
+    pub fn new(player: &Player) -> Result<VJoy> {
         let inp = UninitDevice::new().unwrap();
-        inp.set_name("melee-vpad");
+        inp.set_name(&player.name);
+        inp.set_phys(&format!("{}port{}", discovery::PHYS_PREFIX, player.port));
         inp.enable(EventType::EV_SYN)?;
         inp.enable(EventType::EV_KEY)?;
         inp.enable(EventCode::EV_KEY(EV_KEY::BTN_EAST))?;
@@ -108,48 +114,52 @@ impl VJoy {
         log::info!("Created virtual gamepad device {:?}", device.devnode());
         Ok(VJoy {
             device,
-            now: TimeVal {
-                tv_sec: 0,
-                tv_usec: 0,
-            },
+            now: Cell::new(keyboard::now()),
         })
     }
 
+    const JOY_UP_RANGE_f32: f32 = JOY_UP_RANGE as f32;
+}
+
+impl OutputSink for VJoy {
     #[inline]
-    pub fn sync(&self) {
+    fn sync(&self) {
         self.device.write_event(&InputEvent {
-            time: self.now,
+            time: self.now.get(),
             event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
             value: 0,
         });
     }
 
     #[inline]
-    pub fn key(&self, key: EV_KEY, value: bool) {
+    fn key(&self, key: EV_KEY, value: bool) {
         self.device.write_event(&InputEvent {
-            time: self.now,
+            time: self.now.get(),
             event_code: EventCode::EV_KEY(key),
             value: value as i32,
         });
     }
 
-    const JOY_UP_RANGE_f32: f32 = JOY_UP_RANGE as f32;
-
     #[inline]
-    pub fn joystick(&self, key: EV_ABS, value: I1F7) {
+    fn joystick(&self, key: EV_ABS, value: I1F7) {
         self.device.write_event(&InputEvent {
-            time: self.now,
+            time: self.now.get(),
             event_code: EventCode::EV_ABS(key),
             value: (value.to_num::<f32>() * Self::JOY_UP_RANGE_f32) as i32,
         });
     }
 
     #[inline]
-    pub fn trigger(&self, key: EV_ABS, depth: I1F7) {
+    fn trigger(&self, key: EV_ABS, depth: I1F7) {
         self.device.write_event(&InputEvent {
-            time: self.now,
+            time: self.now.get(),
             event_code: EventCode::EV_ABS(key),
             value: (127.0 + depth.to_num::<f32>() * 128.0) as i32, // makes no sense, but it works :)
         });
     }
+
+    #[inline]
+    fn stamp(&self, time: TimeVal) {
+        self.now.set(time);
+    }
 }